@@ -1,121 +1,517 @@
+use serde::{Deserialize, Serialize};
 use std::{
+    collections::HashSet,
     env,
-    sync::{Arc, RwLock},
+    mem::size_of,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
     time::Instant,
 };
-use tracing::trace;
+use tokio::sync::broadcast;
+use tracing::{info, trace};
 
-use crate::market::ItemListing;
+pub(crate) mod sqlite;
+
+use crate::market::{ItemListing, PlanOptions, PurchasePlan, Sale, SaleHistory};
 
 use std::{collections::HashMap, string::String, time::Duration};
 
-struct CacheValue {
-    data: Vec<ItemListing>,
-    expiration: Instant,
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) enum CacheData {
+    Listings(Vec<ItemListing>),
+    History(SaleHistory),
+    Plan(PurchasePlan),
 }
 
-impl CacheValue {
-    fn is_expired(&self) -> bool {
-        return Instant::now() > self.expiration;
+impl CacheData {
+    //rough bytes held, strings inside listings aren't counted
+    fn size(&self) -> usize {
+        match self {
+            CacheData::Listings(l) => l.len() * size_of::<ItemListing>(),
+            CacheData::History(h) => h.sales.len() * size_of::<Sale>(),
+            //every listing is in there twice, once more in the route
+            CacheData::Plan(p) => 2 * p.listings.len() * size_of::<ItemListing>(),
+        }
     }
 }
 
-pub(crate) struct InMemoryCache {
-    mem: Arc<RwLock<HashMap<String, CacheValue>>>,
+//a cache hit along with how old it is
+pub(crate) struct Cached<T> {
+    pub(crate) data: T,
+    pub(crate) age: Duration,
+    pub(crate) stale: bool,
+}
+
+#[derive(Serialize)]
+pub(crate) struct CacheStats {
+    backend: &'static str,
+    entries: usize,
+    //approximate in memory, the serialized size on disk
+    bytes: usize,
+    max_entries: usize,
+    max_bytes: usize,
+    hits: u64,
+    misses: u64,
+    //entries dropped to make room
+    evictions: u64,
+    //entries dropped because they ran out of time
+    expired: u64,
+}
+
+//cached listings were set or updated, they may or may not differ from before
+#[derive(Clone, Debug)]
+pub(crate) struct ListingsChanged {
+    pub(crate) item_id: usize,
+    pub(crate) location: String,
+}
+
+//settings and counters every backend has
+pub(crate) struct CacheState {
     cache_timeout: u64,
+    //how long listings stay servable after going stale
+    stale_timeout: u64,
+    max_entries: usize,
+    max_bytes: usize,
+    //keys with a background refresh in flight, per process
+    refreshing: Mutex<HashSet<String>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    expired: AtomicU64,
+    changes: broadcast::Sender<ListingsChanged>,
 }
 
-impl InMemoryCache {
-    pub(crate) fn new() -> Self {
+impl CacheState {
+    fn from_env() -> Self {
         Self {
-            mem: Arc::new(RwLock::new(HashMap::new())),
             cache_timeout: env::var("XIVP_CACHE_TIMEOUT")
                 .unwrap_or(String::from("300"))
                 .parse()
                 .unwrap_or(300),
+            stale_timeout: env::var("XIVP_CACHE_STALE_TIMEOUT")
+                .unwrap_or(String::from("900"))
+                .parse()
+                .unwrap_or(900),
+            max_entries: env::var("XIVP_CACHE_MAX_ENTRIES")
+                .unwrap_or(String::from("100000"))
+                .parse()
+                .unwrap_or(100000),
+            max_bytes: env::var("XIVP_CACHE_MAX_BYTES")
+                .unwrap_or(String::from("268435456"))
+                .parse()
+                .unwrap_or(268435456),
+            refreshing: Mutex::new(HashSet::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            expired: AtomicU64::new(0),
+            changes: broadcast::channel(1024).0,
+        }
+    }
+
+    fn listings_changed(&self, changed: ListingsChanged) {
+        //nobody listening is fine
+        let _ = self.changes.send(changed);
+    }
+
+    fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self, backend: &'static str, entries: usize, bytes: usize) -> CacheStats {
+        CacheStats {
+            backend,
+            entries,
+            bytes,
+            max_entries: self.max_entries,
+            max_bytes: self.max_bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            expired: self.expired.load(Ordering::Relaxed),
         }
     }
 }
 
-impl InMemoryCache {
-    pub(crate) fn get_listing(&self, item_id: usize, world: String) -> Option<Vec<ItemListing>> {
-        let id = format!("listing-{world}-{item_id}");
-        trace!("getting {id}");
-        let store = self.mem.read().unwrap();
-        if let Some(val) = store.get(&id) {
-            if val.is_expired() {
-                trace!("Cache expired: {id}");
-                None
-            } else {
-                trace!("Cache hit: {id}");
-                Some(val.data.clone())
+//where market data is kept. backends only store, expire and evict entries,
+//the typed accessors and their keys are the same for all of them
+pub(crate) trait MarketCache: Send + Sync {
+    fn state(&self) -> &CacheState;
+
+    //None once expired, stale entries are still returned
+    fn get(&self, id: &str) -> Option<Cached<CacheData>>;
+
+    //fresh for `timeout` seconds, then servable but stale for another `stale_timeout`.
+    //an entry that is still fresh is left alone. reads see the entry as soon as this returns,
+    //`changed` is announced once it's stored for good
+    fn set(
+        &self,
+        id: String,
+        data: CacheData,
+        timeout: u64,
+        stale_timeout: u64,
+        changed: Option<ListingsChanged>,
+    );
+
+    //changes an entry in place, it keeps going stale and expiring when it would have.
+    //false if there is no such entry
+    fn update(&self, id: &str, f: &mut dyn FnMut(&mut CacheData)) -> bool;
+
+    fn stats(&self) -> CacheStats;
+
+    //drop every expired entry, reads only skip them. returns how many were dropped
+    fn sweep(&self) -> usize;
+
+    //stale listings are still returned, it's up to the caller to refresh them
+    fn get_listing(&self, item_id: usize, world: String) -> Option<Cached<Vec<ItemListing>>> {
+        match self.get(&format!("listing-{world}-{item_id}")) {
+            Some(Cached {
+                data: CacheData::Listings(v),
+                age,
+                stale,
+            }) => Some(Cached {
+                data: v,
+                age,
+                stale,
+            }),
+            _ => None,
+        }
+    }
+
+    fn set_listing(&self, item_id: usize, world: String, data: Vec<ItemListing>) {
+        self.set(
+            format!("listing-{world}-{item_id}"),
+            CacheData::Listings(data),
+            self.state().cache_timeout,
+            self.state().stale_timeout,
+            Some(ListingsChanged {
+                item_id,
+                location: world,
+            }),
+        );
+    }
+
+    //only listings already cached, they are refreshed on their usual schedule regardless
+    fn update_listing(
+        &self,
+        item_id: usize,
+        world: &str,
+        f: &mut dyn FnMut(&mut Vec<ItemListing>),
+    ) -> bool {
+        let updated = self.update(&format!("listing-{world}-{item_id}"), &mut |data| {
+            if let CacheData::Listings(v) = data {
+                f(v)
             }
-        } else {
-            trace!("Cache miss: {id}");
-            None
+        });
+        if updated {
+            self.state().listings_changed(ListingsChanged {
+                item_id,
+                location: world.to_string(),
+            });
         }
+        updated
     }
 
-    pub(crate) fn set_listing(&self, item_id: usize, world: String, data: Vec<ItemListing>) -> () {
-        let id = format!("listing-{world}-{item_id}");
-        trace!("setting {id}");
-        if let Some(_) = self.get_listing(item_id, world) {
-            trace!("Race condition setting {id} :)")
-        } else {
-            self.mem.write().unwrap().insert(
-                id.clone(),
-                CacheValue {
-                    data,
-                    expiration: Instant::now() + Duration::from_secs(self.cache_timeout),
-                },
-            );
+    //every set_listing and update_listing from here on, of any item and location
+    fn subscribe_listings(&self) -> broadcast::Receiver<ListingsChanged> {
+        self.state().changes.subscribe()
+    }
+
+    //false if a refresh of these listings is already running, otherwise the caller owns it until finish_refresh
+    fn start_refresh(&self, item_id: usize, world: &str) -> bool {
+        self.state()
+            .refreshing
+            .lock()
+            .unwrap()
+            .insert(format!("listing-{world}-{item_id}"))
+    }
+
+    fn finish_refresh(&self, item_id: usize, world: &str) {
+        self.state()
+            .refreshing
+            .lock()
+            .unwrap()
+            .remove(&format!("listing-{world}-{item_id}"));
+    }
+
+    //stale history is still returned, it's refreshed along with the listings
+    fn get_history(&self, item_id: usize, world: String) -> Option<Cached<SaleHistory>> {
+        match self.get(&format!("history-{world}-{item_id}")) {
+            Some(Cached {
+                data: CacheData::History(v),
+                age,
+                stale,
+            }) => Some(Cached {
+                data: v,
+                age,
+                stale,
+            }),
+            _ => None,
         }
     }
 
-    pub(crate) fn get_cheapest(
+    fn set_history(&self, item_id: usize, world: String, data: SaleHistory) {
+        self.set(
+            format!("history-{world}-{item_id}"),
+            CacheData::History(data),
+            self.state().cache_timeout,
+            self.state().stale_timeout,
+            None,
+        );
+    }
+
+    //only a plan picked from listings with this listings_version, older ones are left to be replaced
+    fn get_cheapest(
         &self,
         item_id: usize,
         world: String,
         amount: usize,
-        hq: bool,
-    ) -> Option<Vec<ItemListing>> {
-        let id = format!("cheapest-{world}-{item_id}-{amount}-{hq}");
+        options: &PlanOptions,
+        listings_version: u64,
+    ) -> Option<PurchasePlan> {
+        let key = options.key();
+        match self.get(&format!("cheapest-{world}-{item_id}-{amount}-{key}")) {
+            Some(Cached {
+                data: CacheData::Plan(v),
+                ..
+            }) if v.listings_version == listings_version => Some(v),
+            Some(_) => {
+                trace!("listings changed since cheapest-{world}-{item_id}-{amount}-{key}");
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn set_cheapest(
+        &self,
+        item_id: usize,
+        world: String,
+        amount: usize,
+        options: &PlanOptions,
+        data: PurchasePlan,
+    ) {
+        let key = options.key();
+        //never fresh so a plan for newer listings always replaces it,
+        //and gone no later than the listings it was picked from
+        self.set(
+            format!("cheapest-{world}-{item_id}-{amount}-{key}"),
+            CacheData::Plan(data),
+            0,
+            self.state().cache_timeout + self.state().stale_timeout,
+            None,
+        );
+    }
+}
+
+//XIVP_CACHE_BACKEND is memory (the default) or sqlite, kept in the XIVP_CACHE_PATH file
+pub(crate) fn from_env() -> Arc<dyn MarketCache> {
+    match env::var("XIVP_CACHE_BACKEND").as_deref() {
+        Ok("sqlite") => {
+            let path = env::var("XIVP_CACHE_PATH").unwrap_or(String::from("market-cache.sqlite"));
+            info!("using sqlite cache {path}");
+            Arc::new(
+                sqlite::SqliteCache::open(&path)
+                    .unwrap_or_else(|e| panic!("failed to open cache {path}: {e}")),
+            )
+        }
+        _ => Arc::new(InMemoryCache::new()),
+    }
+}
+
+//removes expired entries every XIVP_CACHE_SWEEP_INTERVAL seconds
+pub(crate) async fn run_sweeper(cache: Arc<dyn MarketCache>) {
+    let interval: u64 = env::var("XIVP_CACHE_SWEEP_INTERVAL")
+        .unwrap_or(String::from("60"))
+        .parse()
+        .unwrap_or(60);
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    loop {
+        ticker.tick().await;
+        let removed = cache.sweep();
+        if removed > 0 {
+            info!("cache sweep removed {removed} expired entries");
+        }
+    }
+}
+
+struct CacheValue {
+    data: CacheData,
+    stored: Instant,
+    //past this the entry is stale, it is still served but should be refreshed
+    fresh_until: Instant,
+    expiration: Instant,
+    size: usize,
+    //clock tick of the last read or write, the lowest goes first when the cache is full
+    last_used: AtomicU64,
+}
+
+impl CacheValue {
+    fn is_expired(&self) -> bool {
+        Instant::now() > self.expiration
+    }
+
+    fn is_stale(&self) -> bool {
+        Instant::now() > self.fresh_until
+    }
+}
+
+pub(crate) struct InMemoryCache {
+    mem: Arc<RwLock<HashMap<String, CacheValue>>>,
+    state: CacheState,
+    bytes: AtomicUsize,
+    clock: AtomicU64,
+}
+
+impl InMemoryCache {
+    pub(crate) fn new() -> Self {
+        Self {
+            mem: Arc::new(RwLock::new(HashMap::new())),
+            state: CacheState::from_env(),
+            bytes: AtomicUsize::new(0),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn remove_expired(&self, store: &mut HashMap<String, CacheValue>) -> usize {
+        let mut removed = 0;
+        for (_, v) in store.extract_if(|_, v| v.is_expired()) {
+            self.bytes.fetch_sub(v.size, Ordering::Relaxed);
+            removed += 1;
+        }
+        self.state
+            .expired
+            .fetch_add(removed as u64, Ordering::Relaxed);
+        removed
+    }
+
+    //once over either limit, expired entries go first and then the least recently used,
+    //down to 90% of the limits so this doesn't run again on the very next insert
+    fn evict(&self, store: &mut HashMap<String, CacheValue>) {
+        let over = |store: &HashMap<String, CacheValue>, fraction: usize| {
+            store.len() > self.state.max_entries * fraction / 10
+                || self.bytes.load(Ordering::Relaxed) > self.state.max_bytes * fraction / 10
+        };
+        if !over(store, 10) {
+            return;
+        }
+        self.remove_expired(store);
+        let mut by_use: Vec<(u64, String)> = store
+            .iter()
+            .map(|(k, v)| (v.last_used.load(Ordering::Relaxed), k.clone()))
+            .collect();
+        by_use.sort_unstable();
+        for (_, key) in by_use {
+            if !over(store, 9) {
+                break;
+            }
+            if let Some(v) = store.remove(&key) {
+                self.bytes.fetch_sub(v.size, Ordering::Relaxed);
+                self.state.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl MarketCache for InMemoryCache {
+    fn state(&self) -> &CacheState {
+        &self.state
+    }
+
+    fn get(&self, id: &str) -> Option<Cached<CacheData>> {
         trace!("getting {id}");
         let store = self.mem.read().unwrap();
-        if let Some(val) = store.get(&id) {
+        if let Some(val) = store.get(id) {
             if val.is_expired() {
                 trace!("Cache expired: {id}");
+                self.state.miss();
                 None
             } else {
                 trace!("Cache hit: {id}");
-                Some(val.data.clone())
+                self.state.hit();
+                val.last_used.store(self.tick(), Ordering::Relaxed);
+                Some(Cached {
+                    data: val.data.clone(),
+                    age: val.stored.elapsed(),
+                    stale: val.is_stale(),
+                })
             }
         } else {
             trace!("Cache miss: {id}");
+            self.state.miss();
             None
         }
     }
 
-    pub(crate) fn set_cheapest(
+    fn set(
         &self,
-        item_id: usize,
-        world: String,
-        amount: usize,
-        hq: bool,
-        data: Vec<ItemListing>,
-    ) -> () {
-        let id = format!("cheapest-{world}-{item_id}-{amount}-{hq}");
+        id: String,
+        data: CacheData,
+        timeout: u64,
+        stale_timeout: u64,
+        changed: Option<ListingsChanged>,
+    ) {
         trace!("setting {id}");
-        if let Some(_) = self.get_cheapest(item_id, world, amount, hq) {
-            trace!("Race condition setting {id}")
+        let mut store = self.mem.write().unwrap();
+        if store.get(&id).is_some_and(|v| !v.is_stale()) {
+            trace!("Race condition setting {id} :)")
         } else {
-            self.mem.write().unwrap().insert(
-                id.clone(),
-                CacheValue {
-                    data,
-                    expiration: Instant::now() + Duration::from_secs(3600),
-                },
-            );
+            let now = Instant::now();
+            let size = id.len() + size_of::<CacheValue>() + data.size();
+            self.bytes.fetch_add(size, Ordering::Relaxed);
+            let value = CacheValue {
+                data,
+                stored: now,
+                fresh_until: now + Duration::from_secs(timeout),
+                expiration: now + Duration::from_secs(timeout + stale_timeout),
+                size,
+                last_used: AtomicU64::new(self.tick()),
+            };
+            if let Some(old) = store.insert(id, value) {
+                self.bytes.fetch_sub(old.size, Ordering::Relaxed);
+            }
+            self.evict(&mut store);
+        }
+        drop(store);
+        if let Some(changed) = changed {
+            self.state.listings_changed(changed);
         }
     }
+
+    fn update(&self, id: &str, f: &mut dyn FnMut(&mut CacheData)) -> bool {
+        trace!("updating {id}");
+        let mut store = self.mem.write().unwrap();
+        let Some(val) = store.get_mut(id).filter(|v| !v.is_expired()) else {
+            return false;
+        };
+        f(&mut val.data);
+        let size = id.len() + size_of::<CacheValue>() + val.data.size();
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        self.bytes.fetch_sub(val.size, Ordering::Relaxed);
+        val.size = size;
+        val.last_used.store(self.tick(), Ordering::Relaxed);
+        true
+    }
+
+    fn stats(&self) -> CacheStats {
+        self.state.stats(
+            "memory",
+            self.mem.read().unwrap().len(),
+            self.bytes.load(Ordering::Relaxed),
+        )
+    }
+
+    fn sweep(&self) -> usize {
+        self.remove_expired(&mut self.mem.write().unwrap())
+    }
 }
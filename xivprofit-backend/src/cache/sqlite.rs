@@ -0,0 +1,382 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::Ordering,
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    sync::broadcast,
+};
+use tracing::{trace, warn};
+
+use crate::cache::{CacheData, CacheState, CacheStats, Cached, ListingsChanged, MarketCache};
+
+//most queued writes put in one transaction
+const MAX_WRITE_BATCH: usize = 256;
+
+//queued writes by key until the writer has committed them
+type Pending = Arc<Mutex<HashMap<String, Arc<Write>>>>;
+
+//entries on disk, so restarts come back warm and several instances can share one file.
+//times are unix milliseconds since instances don't share an Instant.
+//writes are queued for a thread of their own, reads block in place, see blocking
+pub(crate) struct SqliteCache {
+    conn: Mutex<Connection>,
+    writes: Mutex<Sender<Arc<Write>>>,
+    //read before the file so an entry can be read back as soon as it's set
+    pending: Pending,
+    //last read of each key since the last sweep, written all at once by it
+    touched: Mutex<HashMap<String, i64>>,
+    state: CacheState,
+}
+
+struct Write {
+    id: String,
+    data: String,
+    stored: i64,
+    fresh_until: i64,
+    expiration: i64,
+    //announced after the commit
+    changed: Option<ListingsChanged>,
+}
+
+//sqlite calls block the thread for as long as the file is locked by someone else,
+//so the runtime is told to move its other tasks off this worker meanwhile
+fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(h) if h.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(f),
+        _ => f(),
+    }
+}
+
+fn open_connection(path: &str) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+    //other instances may be writing the same file
+    conn.busy_timeout(Duration::from_secs(5))?;
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    Ok(conn)
+}
+
+//drains the queue a batch at a time until the cache is dropped
+fn run_writer(
+    mut conn: Connection,
+    writes: Receiver<Arc<Write>>,
+    pending: Pending,
+    changes: broadcast::Sender<ListingsChanged>,
+) {
+    while let Ok(first) = writes.recv() {
+        let mut batch = vec![first];
+        batch.extend(writes.try_iter().take(MAX_WRITE_BATCH - 1));
+        if let Err(e) = write_batch(&mut conn, &batch) {
+            warn!("failed to write {} cache entries: {e}", batch.len());
+        }
+        for w in batch.iter() {
+            unqueue(&pending, w);
+        }
+        for changed in batch.iter().filter_map(|w| w.changed.clone()) {
+            //nobody listening is fine
+            let _ = changes.send(changed);
+        }
+    }
+}
+
+//unless it was set again since
+fn unqueue(pending: &Pending, write: &Arc<Write>) {
+    let mut pending = pending.lock().unwrap();
+    if pending
+        .get(&write.id)
+        .is_some_and(|p| Arc::ptr_eq(p, write))
+    {
+        pending.remove(&write.id);
+    }
+}
+
+//a write that lands on an entry it already wrote is turned down by the fresh_until check,
+//so writing one early in update_inner is fine
+fn write_batch(conn: &mut Connection, batch: &[Arc<Write>]) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    {
+        let mut insert = tx.prepare_cached(
+            "INSERT INTO entries (key, data, size, stored, fresh_until, expiration, last_used)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?4)
+            ON CONFLICT (key) DO UPDATE SET
+                data = excluded.data,
+                size = excluded.size,
+                stored = excluded.stored,
+                fresh_until = excluded.fresh_until,
+                expiration = excluded.expiration,
+                last_used = excluded.last_used
+            WHERE entries.fresh_until < excluded.stored",
+        )?;
+        for w in batch {
+            let written = insert.execute(params![
+                w.id,
+                w.data,
+                (w.id.len() + w.data.len()) as i64,
+                w.stored,
+                w.fresh_until,
+                w.expiration
+            ])?;
+            if written == 0 {
+                trace!("Race condition setting {} :)", w.id);
+            }
+        }
+    }
+    tx.commit()
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+impl SqliteCache {
+    pub(crate) fn open(path: &str) -> rusqlite::Result<Self> {
+        let conn = open_connection(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries (
+                key TEXT PRIMARY KEY,
+                data TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                stored INTEGER NOT NULL,
+                fresh_until INTEGER NOT NULL,
+                expiration INTEGER NOT NULL,
+                last_used INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS entries_expiration ON entries (expiration);
+            CREATE INDEX IF NOT EXISTS entries_last_used ON entries (last_used);",
+        )?;
+        let writer = open_connection(path)?;
+        let (writes, queue) = channel();
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let state = CacheState::from_env();
+        let (p, changes) = (pending.clone(), state.changes.clone());
+        thread::Builder::new()
+            .name(String::from("sqlite-cache-writer"))
+            .spawn(move || run_writer(writer, queue, p, changes))
+            .expect("failed to start the sqlite cache writer");
+        Ok(Self {
+            conn: Mutex::new(conn),
+            writes: Mutex::new(writes),
+            pending,
+            touched: Mutex::new(HashMap::new()),
+            state,
+        })
+    }
+
+    fn totals(conn: &Connection) -> rusqlite::Result<(usize, usize)> {
+        conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM entries",
+            [],
+            |r| Ok((r.get::<_, i64>(0)? as usize, r.get::<_, i64>(1)? as usize)),
+        )
+    }
+
+    fn update_inner(&self, id: &str, f: &mut dyn FnMut(&mut CacheData)) -> rusqlite::Result<bool> {
+        let mut conn = self.conn.lock().unwrap();
+        //an entry still in the queue is written now so the change lands on it
+        let queued = self.pending.lock().unwrap().get(id).cloned();
+        if let Some(w) = queued {
+            write_batch(&mut conn, std::slice::from_ref(&w))?;
+            unqueue(&self.pending, &w);
+        }
+        let data: Option<String> = conn
+            .query_row(
+                "SELECT data FROM entries WHERE key = ?1 AND expiration > ?2",
+                params![id, now()],
+                |r| r.get(0),
+            )
+            .optional()?;
+        let Some(mut data) = data.and_then(|d| serde_json::from_str(&d).ok()) else {
+            return Ok(false);
+        };
+        f(&mut data);
+        let Ok(data) = serde_json::to_string(&data) else {
+            return Ok(false);
+        };
+        conn.execute(
+            "UPDATE entries SET data = ?1, size = ?2, last_used = ?3 WHERE key = ?4",
+            params![data, (id.len() + data.len()) as i64, now(), id],
+        )?;
+        Ok(true)
+    }
+
+    fn sweep_inner(&self) -> rusqlite::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        //reads since the last sweep, so least recently used below is current
+        let touched = std::mem::take(&mut *self.touched.lock().unwrap());
+        if !touched.is_empty() {
+            let tx = conn.transaction()?;
+            {
+                let mut touch = tx.prepare_cached(
+                    "UPDATE entries SET last_used = MAX(last_used, ?1) WHERE key = ?2",
+                )?;
+                for (id, used) in touched.iter() {
+                    touch.execute(params![used, id])?;
+                }
+            }
+            tx.commit()?;
+        }
+        let removed = conn.execute("DELETE FROM entries WHERE expiration <= ?1", [now()])?;
+        self.state
+            .expired
+            .fetch_add(removed as u64, Ordering::Relaxed);
+
+        //least recently used past 90% of either limit. writes don't check the limits, the sweeper does
+        let (entries, bytes) = Self::totals(&conn)?;
+        if entries > self.state.max_entries || bytes > self.state.max_bytes {
+            let evicted = conn.execute(
+                "DELETE FROM entries WHERE key IN (
+                    SELECT key FROM (
+                        SELECT key,
+                            ROW_NUMBER() OVER (ORDER BY last_used DESC) AS n,
+                            SUM(size) OVER (ORDER BY last_used DESC ROWS UNBOUNDED PRECEDING) AS kept
+                        FROM entries
+                    ) WHERE n > ?1 OR kept > ?2
+                )",
+                params![
+                    (self.state.max_entries * 9 / 10) as i64,
+                    (self.state.max_bytes * 9 / 10) as i64
+                ],
+            )?;
+            self.state
+                .evictions
+                .fetch_add(evicted as u64, Ordering::Relaxed);
+        }
+        Ok(removed)
+    }
+}
+
+impl MarketCache for SqliteCache {
+    fn state(&self) -> &CacheState {
+        &self.state
+    }
+
+    fn get(&self, id: &str) -> Option<Cached<CacheData>> {
+        trace!("getting {id}");
+        let now = now();
+        let queued = self
+            .pending
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|w| w.expiration > now)
+            .map(|w| (w.data.clone(), w.stored, w.fresh_until));
+        let row = match queued {
+            Some(row) => Ok(Some(row)),
+            None => blocking(|| {
+                self.conn.lock().unwrap().query_row(
+                "SELECT data, stored, fresh_until FROM entries WHERE key = ?1 AND expiration > ?2",
+                params![id, now],
+                |r| {
+                    Ok((
+                        r.get::<_, String>(0)?,
+                        r.get::<_, i64>(1)?,
+                        r.get::<_, i64>(2)?,
+                    ))
+                },
+            )
+            })
+            .optional(),
+        };
+        let (data, stored, fresh_until) = match row {
+            Ok(Some(row)) => row,
+            Ok(None) => {
+                trace!("Cache miss: {id}");
+                self.state.miss();
+                return None;
+            }
+            Err(e) => {
+                warn!("failed to read cache entry {id}: {e}");
+                self.state.miss();
+                return None;
+            }
+        };
+        //written by an older version, it'll be replaced on the next set
+        let Ok(data) = serde_json::from_str(&data) else {
+            warn!("unreadable cache entry {id}");
+            self.state.miss();
+            return None;
+        };
+        trace!("Cache hit: {id}");
+        self.state.hit();
+        self.touched.lock().unwrap().insert(id.to_string(), now);
+        Some(Cached {
+            data,
+            age: Duration::from_millis(now.saturating_sub(stored).max(0) as u64),
+            stale: now > fresh_until,
+        })
+    }
+
+    fn set(
+        &self,
+        id: String,
+        data: CacheData,
+        timeout: u64,
+        stale_timeout: u64,
+        changed: Option<ListingsChanged>,
+    ) {
+        trace!("setting {id}");
+        let data = match serde_json::to_string(&data) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!("failed to serialize cache entry {id}: {e}");
+                return;
+            }
+        };
+        let now = now();
+        let fresh_until = now + (timeout * 1000) as i64;
+        let write = Arc::new(Write {
+            id,
+            data,
+            stored: now,
+            fresh_until,
+            expiration: fresh_until + (stale_timeout * 1000) as i64,
+            changed,
+        });
+        //held until queued, so the writer can't unqueue it before it's in here
+        let mut pending = self.pending.lock().unwrap();
+        if pending.get(&write.id).is_some_and(|w| w.fresh_until > now) {
+            //the queued one gets announced
+            trace!("Race condition setting {} :)", write.id);
+            return;
+        }
+        pending.insert(write.id.clone(), write.clone());
+        if let Err(e) = self.writes.lock().unwrap().send(write) {
+            warn!("cache writer is gone, dropped {}", e.0.id);
+            pending.remove(&e.0.id);
+        }
+    }
+
+    fn update(&self, id: &str, f: &mut dyn FnMut(&mut CacheData)) -> bool {
+        trace!("updating {id}");
+        blocking(|| self.update_inner(id, f)).unwrap_or_else(|e| {
+            warn!("failed to update cache entry {id}: {e}");
+            false
+        })
+    }
+
+    fn stats(&self) -> CacheStats {
+        let (entries, bytes) = blocking(|| Self::totals(&self.conn.lock().unwrap()))
+            .unwrap_or_else(|e| {
+                warn!("failed to count cache entries: {e}");
+                (0, 0)
+            });
+        self.state.stats("sqlite", entries, bytes)
+    }
+
+    fn sweep(&self) -> usize {
+        blocking(|| self.sweep_inner()).unwrap_or_else(|e| {
+            warn!("cache sweep failed: {e}");
+            0
+        })
+    }
+}
@@ -1,11 +1,69 @@
 use log::{error, info};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+};
+
+pub(crate) mod planner;
+pub(crate) mod source;
+
+use source::{DataError, DataSource};
 
 #[derive(Clone, Serialize)]
 pub(crate) struct Item {
     pub(crate) name: String,
     pub(crate) id: usize,
+    pub(crate) can_be_hq: bool,
+    pub(crate) stack_size: usize,
+    //ItemUICategory row
+    pub(crate) category: usize,
+    //can't be sold on the market board
+    pub(crate) untradable: bool,
+    //what a vendor sells it for, and buys it back for
+    pub(crate) vendor_price: usize,
+    pub(crate) vendor_sell_price: usize,
+    pub(crate) item_level: usize,
+    //names of the gil shops selling it
+    pub(crate) gil_shops: Vec<String>,
+}
+
+impl Item {
+    //unit price at a gil shop, they never run out
+    pub(crate) fn vendor_offer(&self) -> Option<usize> {
+        (!self.gil_shops.is_empty() && self.vendor_price > 0).then_some(self.vendor_price)
+    }
+}
+//the crafting classes, in CraftType order
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Job {
+    Carpenter,
+    Blacksmith,
+    Armorer,
+    Goldsmith,
+    Leatherworker,
+    Weaver,
+    Alchemist,
+    Culinarian,
 }
+
+impl Job {
+    fn from_craft_type(craft_type: i32) -> Option<Self> {
+        match craft_type {
+            0 => Some(Job::Carpenter),
+            1 => Some(Job::Blacksmith),
+            2 => Some(Job::Armorer),
+            3 => Some(Job::Goldsmith),
+            4 => Some(Job::Leatherworker),
+            5 => Some(Job::Weaver),
+            6 => Some(Job::Alchemist),
+            7 => Some(Job::Culinarian),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Serialize, Debug)]
 pub(crate) struct Recipe {
     pub(crate) id: usize,
@@ -13,6 +71,36 @@ pub(crate) struct Recipe {
     pub(crate) result_item_quantity: usize,
     //itemid, quantity
     pub(crate) ingredients: Vec<(usize, usize)>,
+    //None for a CraftType that isn't one of the crafting classes
+    pub(crate) job: Option<Job>,
+    //RecipeLevelTable row
+    pub(crate) recipe_level: usize,
+    //class level needed to craft it
+    pub(crate) level: usize,
+    pub(crate) stars: usize,
+    //the RecipeLevelTable values scaled by the recipe's factors
+    pub(crate) difficulty: usize,
+    pub(crate) quality: usize,
+    pub(crate) durability: usize,
+    pub(crate) required_craftsmanship: usize,
+    pub(crate) required_control: usize,
+}
+
+//which recipes a crafter can actually make, everything when empty
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct RecipeFilter {
+    pub(crate) job: Option<Job>,
+    pub(crate) max_level: Option<usize>,
+}
+
+impl RecipeFilter {
+    pub(crate) fn matches(&self, recipe: &Recipe) -> bool {
+        self.allows(recipe.job, recipe.level)
+    }
+
+    pub(crate) fn allows(&self, job: Option<Job>, level: usize) -> bool {
+        self.job.is_none_or(|j| job == Some(j)) && self.max_level.is_none_or(|l| level <= l)
+    }
 }
 
 //#[derive(Clone)]
@@ -20,6 +108,39 @@ pub(crate) struct ItemData {
     pub(crate) items: Vec<Item>,
     pub(crate) recipes: Vec<Recipe>,
     pub(crate) craftable_items: Vec<Item>,
+    //item id to its index in items
+    item_index: HashMap<usize, usize>,
+}
+
+//the live ItemData, replaced as a whole on reload so readers keep whichever snapshot they loaded
+pub(crate) struct SharedItemData {
+    current: RwLock<Arc<ItemData>>,
+}
+
+impl SharedItemData {
+    pub(crate) fn new(data: ItemData) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(data)),
+        }
+    }
+
+    pub(crate) fn load(&self) -> Arc<ItemData> {
+        self.current.read().unwrap().clone()
+    }
+
+    pub(crate) fn store(&self, data: Arc<ItemData>) {
+        *self.current.write().unwrap() = data;
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct ItemDataDiff {
+    pub(crate) added_items: Vec<Item>,
+    pub(crate) removed_items: Vec<Item>,
+    pub(crate) added_recipes: Vec<usize>,
+    pub(crate) removed_recipes: Vec<usize>,
+    pub(crate) item_count: usize,
+    pub(crate) recipe_count: usize,
 }
 
 #[derive(Clone, Deserialize)]
@@ -28,6 +149,35 @@ struct ItemCsvRow {
     name: String,
     #[serde(rename = "#")]
     id: usize,
+    //older sheets may not have these, items are assumed to be HQ-able and tradable then
+    #[serde(rename = "CanBeHq", default = "yes", deserialize_with = "csv_bool")]
+    can_be_hq: bool,
+    #[serde(rename = "StackSize", default)]
+    stack_size: usize,
+    #[serde(rename = "ItemUICategory", default)]
+    category: usize,
+    #[serde(rename = "IsUntradable", default, deserialize_with = "csv_bool")]
+    untradable: bool,
+    #[serde(rename = "Price{Mid}", default)]
+    price_mid: usize,
+    #[serde(rename = "Price{Low}", default)]
+    price_low: usize,
+    #[serde(rename = "Level{Item}", default)]
+    item_level: usize,
+}
+
+fn yes() -> bool {
+    true
+}
+
+//the sheets spell booleans True and False
+fn csv_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    match value.as_str() {
+        "True" | "true" | "1" => Ok(true),
+        "False" | "false" | "0" => Ok(false),
+        _ => Err(serde::de::Error::custom(format!("not a boolean: {value}"))),
+    }
 }
 
 #[derive(Deserialize)]
@@ -70,6 +220,55 @@ struct RecipeCsvRow {
     ingredient_6_amount: i32,
     #[serde(rename = "Amount{Ingredient}[7]")]
     ingredient_7_amount: i32,
+    //older sheets may not have these
+    #[serde(rename = "CraftType", default)]
+    craft_type: i32,
+    #[serde(rename = "RecipeLevelTable", default)]
+    recipe_level_table: i32,
+    //percentages of the RecipeLevelTable values
+    #[serde(rename = "DifficultyFactor", default)]
+    difficulty_factor: i32,
+    #[serde(rename = "QualityFactor", default)]
+    quality_factor: i32,
+    #[serde(rename = "DurabilityFactor", default)]
+    durability_factor: i32,
+    #[serde(rename = "RequiredCraftsmanship", default)]
+    required_craftsmanship: i32,
+    #[serde(rename = "RequiredControl", default)]
+    required_control: i32,
+}
+
+#[derive(Deserialize)]
+struct GilShopCsvRow {
+    #[serde(rename = "#")]
+    id: usize,
+    #[serde(rename = "Name")]
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct GilShopItemCsvRow {
+    //{shop}.{index}
+    #[serde(rename = "#")]
+    id: String,
+    #[serde(rename = "Item")]
+    item_id: usize,
+}
+
+#[derive(Deserialize)]
+struct RecipeLevelCsvRow {
+    #[serde(rename = "#")]
+    id: i32,
+    #[serde(rename = "ClassJobLevel")]
+    class_job_level: i32,
+    #[serde(rename = "Stars")]
+    stars: i32,
+    #[serde(rename = "Difficulty")]
+    difficulty: i32,
+    #[serde(rename = "Quality")]
+    quality: i32,
+    #[serde(rename = "Durability")]
+    durability: i32,
 }
 
 impl From<ItemCsvRow> for Item {
@@ -77,12 +276,21 @@ impl From<ItemCsvRow> for Item {
         Self {
             id: value.id,
             name: value.name,
+            can_be_hq: value.can_be_hq,
+            stack_size: value.stack_size,
+            category: value.category,
+            untradable: value.untradable,
+            vendor_price: value.price_mid,
+            vendor_sell_price: value.price_low,
+            item_level: value.item_level,
+            gil_shops: Vec::new(),
         }
     }
 }
 
-impl From<RecipeCsvRow> for Recipe {
-    fn from(value: RecipeCsvRow) -> Self {
+impl Recipe {
+    //a recipe without its RecipeLevelTable row comes out as level 0 with no difficulty
+    fn from_csv(value: RecipeCsvRow, levels: &HashMap<i32, RecipeLevelCsvRow>) -> Self {
         let mut ingredients = Vec::new();
         if value.ingredient_0_amount > 0 {
             ingredients.push((
@@ -132,19 +340,107 @@ impl From<RecipeCsvRow> for Recipe {
                 value.ingredient_7_amount as usize,
             ));
         }
+        let level = levels.get(&value.recipe_level_table);
+        let scaled = |base: Option<i32>, factor: i32| (base.unwrap_or(0) * factor / 100) as usize;
         Self {
             id: value.id as usize,
             result_item_id: value.result_item_id as usize,
             result_item_quantity: value.result_item_amount as usize,
             ingredients,
+            job: Job::from_craft_type(value.craft_type),
+            recipe_level: value.recipe_level_table as usize,
+            level: level.map(|l| l.class_job_level as usize).unwrap_or(0),
+            stars: level.map(|l| l.stars as usize).unwrap_or(0),
+            difficulty: scaled(level.map(|l| l.difficulty), value.difficulty_factor),
+            quality: scaled(level.map(|l| l.quality), value.quality_factor),
+            durability: scaled(level.map(|l| l.durability), value.durability_factor),
+            required_craftsmanship: value.required_craftsmanship as usize,
+            required_control: value.required_control as usize,
         }
     }
 }
 
 impl ItemData {
-    pub(crate) async fn new() -> Self {
-        info!("loading ItemData");
-        let (item_data, recipe_data) = get_data().await;
+    pub(crate) fn get_item(&self, item_id: usize) -> Option<&Item> {
+        self.item_index.get(&item_id).map(|i| &self.items[*i])
+    }
+
+    pub(crate) fn get_recipes_for_item(&self, item_id: usize) -> Vec<&Recipe> {
+        self.recipes
+            .iter()
+            .filter(|r| r.result_item_id == item_id)
+            .collect()
+    }
+
+    //sanity checks before a freshly loaded ItemData replaces `previous`
+    pub(crate) fn validate(&self, previous: &ItemData) -> Result<(), String> {
+        if self.items.is_empty() || self.recipes.is_empty() {
+            return Err(String::from("no items or recipes loaded"));
+        }
+        //a truncated download still parses, it just has far fewer rows
+        if self.items.len() < previous.items.len() / 2
+            || self.recipes.len() < previous.recipes.len() / 2
+        {
+            return Err(format!(
+                "item/recipe count dropped from {}/{} to {}/{}",
+                previous.items.len(),
+                previous.recipes.len(),
+                self.items.len(),
+                self.recipes.len()
+            ));
+        }
+        let item_ids: HashSet<usize> = self.items.iter().map(|i| i.id).collect();
+        if let Some(r) = self.recipes.iter().find(|r| {
+            !item_ids.contains(&r.result_item_id)
+                || r.ingredients.iter().any(|(id, _)| !item_ids.contains(id))
+        }) {
+            return Err(format!("recipe {} references unknown items", r.id));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn diff(&self, previous: &ItemData) -> ItemDataDiff {
+        let old_items: HashSet<usize> = previous.items.iter().map(|i| i.id).collect();
+        let new_items: HashSet<usize> = self.items.iter().map(|i| i.id).collect();
+        let old_recipes: HashSet<usize> = previous.recipes.iter().map(|r| r.id).collect();
+        let new_recipes: HashSet<usize> = self.recipes.iter().map(|r| r.id).collect();
+        ItemDataDiff {
+            added_items: self
+                .items
+                .iter()
+                .filter(|i| !old_items.contains(&i.id))
+                .cloned()
+                .collect(),
+            removed_items: previous
+                .items
+                .iter()
+                .filter(|i| !new_items.contains(&i.id))
+                .cloned()
+                .collect(),
+            added_recipes: self
+                .recipes
+                .iter()
+                .filter(|r| !old_recipes.contains(&r.id))
+                .map(|r| r.id)
+                .collect(),
+            removed_recipes: previous
+                .recipes
+                .iter()
+                .filter(|r| !new_recipes.contains(&r.id))
+                .map(|r| r.id)
+                .collect(),
+            item_count: self.items.len(),
+            recipe_count: self.recipes.len(),
+        }
+    }
+
+    pub(crate) async fn new(source: &DataSource) -> Result<Self, DataError> {
+        info!("loading ItemData from {source:?}");
+        let item_data = source.load_sheet("Item").await?;
+        let recipe_data = source.load_sheet("Recipe").await?;
+        let recipe_level_data = source.load_sheet("RecipeLevelTable").await?;
+        let gil_shop_data = source.load_sheet("GilShop").await?;
+        let gil_shop_item_data = source.load_sheet("GilShopItem").await?;
         //item data
         let item_data = clean_csv(item_data);
         let mut item_csv = csv::Reader::from_reader(item_data.as_bytes());
@@ -160,6 +456,64 @@ impl ItemData {
                 }
             }
         }
+        //gil shop data
+        let gil_shop_data = clean_csv(gil_shop_data);
+        let mut gil_shop_csv = csv::Reader::from_reader(gil_shop_data.as_bytes());
+        let mut shops = HashMap::new();
+        for result in gil_shop_csv.deserialize() {
+            match result {
+                Ok(s) => {
+                    let shop: GilShopCsvRow = s;
+                    shops.insert(shop.id, shop.name);
+                }
+                Err(e) => {
+                    error!("{e}");
+                }
+            }
+        }
+        let gil_shop_item_data = clean_csv(gil_shop_item_data);
+        let mut gil_shop_item_csv = csv::Reader::from_reader(gil_shop_item_data.as_bytes());
+        let mut sold_at: HashMap<usize, Vec<String>> = HashMap::new();
+        for result in gil_shop_item_csv.deserialize() {
+            match result {
+                Ok(s) => {
+                    let shop_item: GilShopItemCsvRow = s;
+                    let shop_id = shop_item.id.split('.').next().and_then(|s| s.parse().ok());
+                    let Some(name) = shop_id.and_then(|id: usize| shops.get(&id)) else {
+                        continue;
+                    };
+                    let names = sold_at.entry(shop_item.item_id).or_default();
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+                Err(e) => {
+                    error!("{e}");
+                }
+            }
+        }
+        for item in items.iter_mut() {
+            if let Some(names) = sold_at.remove(&item.id) {
+                item.gil_shops = names;
+            }
+        }
+        //recipe level data
+        let recipe_level_data = clean_csv(recipe_level_data);
+        let mut recipe_level_csv = csv::Reader::from_reader(recipe_level_data.as_bytes());
+        let mut levels = HashMap::new();
+        for result in recipe_level_csv.deserialize() {
+            match result {
+                Ok(l) => {
+                    let level: RecipeLevelCsvRow = l;
+                    levels.insert(level.id, level);
+                }
+                Err(e) => {
+                    error!("{e}");
+                }
+            }
+        }
+        let item_index: HashMap<usize, usize> =
+            items.iter().enumerate().map(|(i, x)| (x.id, i)).collect();
         //recipe data
         let recipe_data = clean_csv(recipe_data);
         let mut recipe_csv = csv::Reader::from_reader(recipe_data.as_bytes());
@@ -169,14 +523,20 @@ impl ItemData {
             match result {
                 Ok(i) => {
                     let recipe: RecipeCsvRow = i;
-                    let processed_recipe = Recipe::from(recipe);
-                    if processed_recipe.ingredients.len() > 0 {
-                        let items = items.clone();
-                        let item = items
-                            .iter()
-                            .find(|i| i.id == processed_recipe.result_item_id);
+                    let processed_recipe = Recipe::from_csv(recipe, &levels);
+                    //a sheet row without a result amount makes nothing, every cost per unit would divide by zero
+                    if !processed_recipe.ingredients.is_empty()
+                        && processed_recipe.result_item_quantity > 0
+                    {
+                        //kept in recipes so validate turns down a reload with it
+                        match item_index.get(&processed_recipe.result_item_id) {
+                            Some(i) => craftable_items.push(items[*i].clone()),
+                            None => error!(
+                                "recipe {} makes unknown item {}",
+                                processed_recipe.id, processed_recipe.result_item_id
+                            ),
+                        }
                         recipes.push(processed_recipe);
-                        craftable_items.push(item.unwrap().clone());
                     }
                 }
                 Err(e) => {
@@ -185,40 +545,15 @@ impl ItemData {
             }
         }
         info!("finished loading ItemData");
-        Self {
+        Ok(Self {
+            item_index,
             items,
             recipes,
             craftable_items,
-        }
+        })
     }
 }
 
-async fn get_data() -> (String, String) {
-    let client = reqwest::Client::new();
-    let item_data: String = client
-        .get("https://raw.githubusercontent.com/viion/ffxiv-datamining/master/csv/Item.csv")
-        .send()
-        .await
-        .expect("failed to download item data from github")
-        .text()
-        .await
-        .expect("failed to decode github response");
-
-    let recipe_data = client
-        .get("https://raw.githubusercontent.com/viion/ffxiv-datamining/master/csv/Recipe.csv")
-        .send()
-        .await
-        .expect("failed to download item data from github")
-        .text()
-        .await
-        .expect("failed to decode github response");
-
-    // let item_data_length = item_data.chars().count();
-    // let recipe_data_length = recipe_data.chars().count();
-    // info!("item_data length: {item_data_length}, recipe_data length: {recipe_data_length}");
-    (item_data, recipe_data)
-}
-
 fn clean_csv(mut data: String) -> String {
     //remove first line (indexes)
     data = data
@@ -241,6 +576,5 @@ fn clean_csv(mut data: String) -> String {
         .1
         .to_string();
     //replace header row
-    let data = [header_row, data].concat();
-    data
+    [header_row, data].concat()
 }
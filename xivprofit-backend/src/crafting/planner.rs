@@ -0,0 +1,180 @@
+use futures::{
+    future::{join_all, BoxFuture},
+    FutureExt,
+};
+use log::trace;
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::{
+    cache::MarketCache,
+    crafting::ItemData,
+    market::{
+        self, ItemListing, MarketError, PlanOptions, PurchasePlan, RunningJobs, TravelOptions,
+        MAX_AMOUNT,
+    },
+};
+
+//recipe trees in the game are shallow, this only guards against runaway recursion
+const MAX_DEPTH: usize = 8;
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum PlanStep {
+    Buy {
+        listings: Vec<ItemListing>,
+    },
+    Craft {
+        recipe_id: usize,
+        crafts: usize,
+        ingredients: Vec<CraftPlan>,
+    },
+    //can't be bought in full and can't be crafted
+    Unavailable,
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct CraftPlan {
+    pub(crate) item_id: usize,
+    pub(crate) amount: usize,
+    //None when the step is Unavailable
+    pub(crate) cost: Option<usize>,
+    //what buying everything off the market board would have cost, for comparison
+    pub(crate) buy_cost: Option<usize>,
+    pub(crate) step: PlanStep,
+}
+
+//cheapest way to end up with `amount` of `item_id`, crafting intermediates wherever that beats buying them
+pub(crate) async fn get_cheapest_plan(
+    item_id: usize,
+    amount: usize,
+    location: &String,
+    hq: bool,
+    item_data: &ItemData,
+    cache: &Arc<dyn MarketCache>,
+    jobs: &RunningJobs,
+) -> Result<CraftPlan, MarketError> {
+    plan(
+        item_id,
+        amount,
+        location,
+        hq,
+        item_data,
+        cache,
+        jobs,
+        Vec::new(),
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+fn plan<'a>(
+    item_id: usize,
+    amount: usize,
+    location: &'a String,
+    hq: bool,
+    item_data: &'a ItemData,
+    cache: &'a Arc<dyn MarketCache>,
+    jobs: &'a RunningJobs,
+    //items currently being crafted above this node, some recipes loop back on themselves
+    path: Vec<usize>,
+) -> BoxFuture<'a, Result<CraftPlan, MarketError>> {
+    async move {
+        //amounts multiply down the tree, past the bound nothing is planned for it
+        if amount > MAX_AMOUNT {
+            trace!("too many of item_id:{item_id} needed: {amount}");
+            return Ok(CraftPlan {
+                item_id,
+                amount,
+                cost: None,
+                buy_cost: None,
+                step: PlanStep::Unavailable,
+            });
+        }
+        let options = PlanOptions {
+            hq: hq.into(),
+            leftovers: None,
+            travel: TravelOptions::default(),
+            vendor_price: None,
+        }
+        .for_item(item_data.get_item(item_id));
+        let purchase = match market::get_cheapest_combination(
+            item_id,
+            location.clone(),
+            cache,
+            amount,
+            options,
+            jobs,
+        )
+        .await
+        {
+            //not on the market board, it can still be crafted
+            Err(MarketError::ItemNotFound(_)) => PurchasePlan::new(Vec::new(), amount, 0.0),
+            r => r?,
+        };
+        let buy_cost = purchase.fulfilled.then_some(purchase.total_cost);
+        let mut best = CraftPlan {
+            item_id,
+            amount,
+            cost: buy_cost,
+            buy_cost,
+            step: match buy_cost {
+                Some(_) => PlanStep::Buy {
+                    listings: purchase.listings,
+                },
+                None => PlanStep::Unavailable,
+            },
+        };
+
+        if path.len() >= MAX_DEPTH || path.contains(&item_id) {
+            trace!("not expanding item_id:{item_id} depth:{}", path.len());
+            return Ok(best);
+        }
+        let mut path = path;
+        path.push(item_id);
+
+        let recipes = item_data.get_recipes_for_item(item_id);
+        //fetch every ingredient one level down in one go before recursing into them
+        let ingredient_ids: Vec<usize> = recipes
+            .iter()
+            .flat_map(|r| r.ingredients.iter().map(|(id, _)| *id))
+            .collect();
+        market::get_items_listings(location, &ingredient_ids, cache).await?;
+        for recipe in recipes {
+            let crafts = amount.div_ceil(recipe.result_item_quantity);
+            let ingredients = join_all(recipe.ingredients.iter().map(|(id, qty)| {
+                plan(
+                    *id,
+                    qty.saturating_mul(crafts),
+                    location,
+                    hq,
+                    item_data,
+                    cache,
+                    jobs,
+                    path.clone(),
+                )
+            }))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+            let craft_cost: Option<usize> = ingredients.iter().map(|i| i.cost).sum();
+            if let Some(c) = craft_cost {
+                if best.cost.is_none_or(|b| c < b) {
+                    best = CraftPlan {
+                        item_id,
+                        amount,
+                        cost: Some(c),
+                        buy_cost,
+                        step: PlanStep::Craft {
+                            recipe_id: recipe.id,
+                            crafts,
+                            ingredients,
+                        },
+                    };
+                }
+            }
+        }
+        Ok(best)
+    }
+    .boxed()
+}
@@ -0,0 +1,108 @@
+use log::{info, warn};
+use std::{
+    env, fmt,
+    path::{Path, PathBuf},
+};
+
+const DATAMINING_REPO: &str = "https://raw.githubusercontent.com/viion/ffxiv-datamining";
+
+//where the game data sheets (Item.csv, Recipe.csv, ...) are read from
+#[derive(Clone, Debug)]
+pub(crate) enum DataSource {
+    //a local checkout of the csv folder
+    Directory(PathBuf),
+    //a commit or tag of the datamining repo, downloaded once and then served from the cache dir
+    Snapshot(String),
+    //any base url serving {sheet}.csv, the last good download is kept in the cache dir
+    Url(String),
+}
+
+#[derive(Debug)]
+pub(crate) enum DataError {
+    Io(PathBuf, std::io::Error),
+    Download(String, reqwest::Error),
+}
+
+impl fmt::Display for DataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataError::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            DataError::Download(url, e) => write!(f, "failed to download {url}: {e}"),
+        }
+    }
+}
+
+impl DataSource {
+    //XIVP_DATA_SOURCE is one of dir:<path>, snapshot:<git ref> or url:<base url>
+    pub(crate) fn from_env() -> Self {
+        let source = env::var("XIVP_DATA_SOURCE").unwrap_or_default();
+        match source.split_once(':') {
+            Some(("dir", path)) => DataSource::Directory(PathBuf::from(path)),
+            Some(("snapshot", rev)) => DataSource::Snapshot(rev.to_string()),
+            Some(("url", url)) => DataSource::Url(url.trim_end_matches('/').to_string()),
+            _ => {
+                if !source.is_empty() {
+                    warn!("unrecognized XIVP_DATA_SOURCE {source}, using github master");
+                }
+                DataSource::Url(format!("{DATAMINING_REPO}/master/csv"))
+            }
+        }
+    }
+
+    pub(crate) async fn load_sheet(&self, sheet: &str) -> Result<String, DataError> {
+        let file_name = format!("{sheet}.csv");
+        match self {
+            DataSource::Directory(dir) => read(&dir.join(file_name)),
+            DataSource::Snapshot(rev) => {
+                let cached = cache_dir().join(rev).join(&file_name);
+                if cached.exists() {
+                    return read(&cached);
+                }
+                let data = download(&format!("{DATAMINING_REPO}/{rev}/csv/{file_name}")).await?;
+                write_cache(&cached, &data);
+                Ok(data)
+            }
+            DataSource::Url(base) => {
+                let cached = cache_dir().join("latest").join(&file_name);
+                match download(&format!("{base}/{file_name}")).await {
+                    Ok(data) => {
+                        write_cache(&cached, &data);
+                        Ok(data)
+                    }
+                    Err(e) if cached.exists() => {
+                        warn!("{e}, falling back to cached {}", cached.display());
+                        read(&cached)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        }
+    }
+}
+
+fn cache_dir() -> PathBuf {
+    PathBuf::from(env::var("XIVP_DATA_CACHE_DIR").unwrap_or(String::from("data-cache")))
+}
+
+fn read(path: &Path) -> Result<String, DataError> {
+    info!("reading {}", path.display());
+    std::fs::read_to_string(path).map_err(|e| DataError::Io(path.to_path_buf(), e))
+}
+
+async fn download(url: &str) -> Result<String, DataError> {
+    info!("downloading {url}");
+    let send = async { reqwest::get(url).await?.error_for_status()?.text().await };
+    send.await
+        .map_err(|e| DataError::Download(url.to_string(), e))
+}
+
+//failing to cache is not fatal, the next boot will just download again
+fn write_cache(path: &Path, data: &str) {
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(path, data));
+    if let Err(e) = result {
+        warn!("failed to cache {}: {e}", path.display());
+    }
+}
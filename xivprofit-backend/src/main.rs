@@ -1,16 +1,20 @@
 mod cache;
 mod crafting;
 mod market;
+mod profit;
 mod web;
 
-use cache::InMemoryCache;
-use crafting::ItemData;
+use crafting::{source::DataSource, ItemData, SharedItemData};
 use dotenvy::dotenv;
 use log::info;
-use std::{collections::HashMap, env};
+use profit::scan;
 use std::sync::Arc;
+use std::{collections::HashMap, env};
 
-use axum::{routing::get, Router};
+use axum::{
+    routing::{get, post},
+    Router,
+};
 use tower_http::cors::CorsLayer;
 
 #[tokio::main]
@@ -20,20 +24,42 @@ async fn main() {
     // initialize tracing
     tracing_subscriber::fmt::init();
     let ctx = web::Context {
-        cache: Arc::new(InMemoryCache::new()),
-        item_data: Arc::new(ItemData::new().await),
+        cache: cache::from_env(),
+        item_data: Arc::new(SharedItemData::new(
+            ItemData::new(&DataSource::from_env())
+                .await
+                .unwrap_or_else(|e| panic!("failed to load item data: {e}")),
+        )),
+        reloading: Arc::new(().into()),
         jobs: Arc::new(HashMap::new().into()),
+        scans: Arc::new(HashMap::new().into()),
     };
+    tokio::spawn(cache::run_sweeper(ctx.cache.clone()));
+    tokio::spawn(market::live::run_subscriber(ctx.cache.clone()));
+    tokio::spawn(scan::run_scheduler(
+        ctx.item_data.clone(),
+        ctx.cache.clone(),
+        ctx.jobs.clone(),
+        ctx.scans.clone(),
+    ));
 
     // build our application with a route
     let app = Router::new()
         .route("/api/listings", get(web::get_listings))
+        .route("/api/listings/batch", get(web::get_listings_batch))
+        .route("/api/cache/stats", get(web::get_cache_stats))
+        .route("/api/stream", get(web::get_stream))
+        .route("/api/history", get(web::get_history))
         .route("/api/items", get(web::get_items))
         .route("/api/recipes", get(web::get_recipes))
-        //.route("/api/profit", get(web::get_profit))
+        .route("/api/profit", get(web::get_profit))
+        .route("/api/craftplan", get(web::get_craft_plan))
+        .route("/api/top_profits", get(web::get_top_profits))
         .route("/api/craftable_items", get(web::get_craftable_items))
         .route("/api/cheapestlistings", get(web::get_cheapest_listings))
         .route("/api/saleprice", get(web::get_saleprice))
+        .route("/api/arbitrage", get(web::get_arbitrage))
+        .route("/api/admin/reload", post(web::reload_item_data))
         .with_state(ctx)
         .layer(CorsLayer::permissive());
 
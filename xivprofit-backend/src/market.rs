@@ -1,18 +1,80 @@
 use futures::{
-    future::{BoxFuture, Shared},
+    future::{join_all, BoxFuture, Shared},
     FutureExt,
 };
-use log::trace;
-use reqwest::{Error, StatusCode};
+use log::{trace, warn};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, env, sync::Arc};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    env, fmt,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::Mutex;
 
+pub(crate) mod arbitrage;
+mod client;
+pub(crate) mod estimator;
+pub(crate) mod live;
+mod locations;
 mod optimizer;
+pub(crate) mod route;
+pub(crate) mod tax;
+#[cfg(test)]
+mod testing;
+
+use crate::{
+    cache::{Cached, MarketCache},
+    crafting::Item,
+};
+use estimator::{EstimatorKind, Quality, SalePriceEstimate};
 
-use crate::cache::InMemoryCache;
+//optimizer runs currently in flight, keyed the same way as their cache entries
+pub(crate) type RunningJobs =
+    Arc<Mutex<HashMap<String, Shared<BoxFuture<'static, Result<PurchasePlan, MarketError>>>>>>;
 
-#[derive(Clone, Serialize)]
+//Clone so a failed optimizer run can be handed to everyone waiting on it
+#[derive(Clone, Debug)]
+pub(crate) enum MarketError {
+    //universalis has no data for this item id
+    ItemNotFound(usize),
+    //not a world, data center or region universalis knows
+    InvalidLocation(String),
+    //parameters that can't be served, says which
+    InvalidRequest(String),
+    //universalis answered 429, retry_after is in seconds when it said so
+    RateLimited { retry_after: Option<u64> },
+    //anything else: 5xx, timeouts, responses that don't parse
+    Upstream(String),
+}
+
+impl fmt::Display for MarketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MarketError::ItemNotFound(id) => write!(f, "no market data for item {id}"),
+            MarketError::InvalidLocation(l) => write!(f, "unknown world or data center {l}"),
+            MarketError::InvalidRequest(e) => write!(f, "invalid request: {e}"),
+            MarketError::RateLimited { .. } => write!(f, "rate limited by universalis"),
+            MarketError::Upstream(e) => write!(f, "universalis request failed: {e}"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for MarketError {
+    fn from(e: reqwest::Error) -> Self {
+        MarketError::Upstream(e.to_string())
+    }
+}
+
+//vendors sell on every world, their listings aren't tied to one
+pub(crate) const VENDOR_WORLD: usize = 0;
+
+//most of one item a plan is picked for, the optimizer's tables grow with it
+pub(crate) const MAX_AMOUNT: usize = 1000;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct ItemListing {
     item_id: usize,
     world_id: usize,
@@ -21,6 +83,225 @@ pub(crate) struct ItemListing {
     pub(crate) total_price: usize,
     hq: bool,
     retainer_name: String, //or npc vendor name
+    //sold by an NPC, on VENDOR_WORLD and in any quantity
+    #[serde(default)]
+    pub(crate) vendor: bool,
+}
+
+impl ItemListing {
+    pub(crate) fn vendor(item_id: usize, price: usize, quantity: usize) -> Self {
+        Self {
+            item_id,
+            world_id: VENDOR_WORLD,
+            price_per_unit: price as f32,
+            quantity,
+            total_price: price * quantity,
+            hq: false,
+            retainer_name: String::from("NPC vendor"),
+            vendor: true,
+        }
+    }
+}
+
+//changes whenever a refresh changes the listings, so anything computed from them can tell it's out of date
+pub(crate) fn listings_version(listings: &[ItemListing]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for l in listings {
+        l.item_id.hash(&mut hasher);
+        l.world_id.hash(&mut hasher);
+        l.price_per_unit.to_bits().hash(&mut hasher);
+        l.quantity.hash(&mut hasher);
+        l.total_price.hash(&mut hasher);
+        l.hq.hash(&mut hasher);
+        l.retainer_name.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum HqMode {
+    Any,
+    //all HQ if the market can supply it, otherwise the cheapest plan regardless of quality
+    Prefer,
+    //at least this many HQ units, the rest may be NQ
+    AtLeast(usize),
+    //as many HQ units as this much gil on top of the cheapest plan buys
+    Budget(usize),
+}
+
+impl From<bool> for HqMode {
+    fn from(hq: bool) -> Self {
+        match hq {
+            true => HqMode::Prefer,
+            false => HqMode::Any,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TravelOptions {
+    //gil a visit to another world is worth avoiding
+    pub(crate) world_penalty: usize,
+    pub(crate) max_worlds: Option<usize>,
+    //visited first and never penalized
+    pub(crate) home_world: Option<usize>,
+    //shop on these worlds instead of picking them per item, set when they were picked for more than one item
+    pub(crate) worlds: Option<Vec<usize>>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PlanOptions {
+    pub(crate) hq: HqMode,
+    //value units bought past the requested amount at this estimate of their sale price
+    pub(crate) leftovers: Option<SalePriceEstimate>,
+    pub(crate) travel: TravelOptions,
+    //unit price at a gil shop, if the item is sold at one
+    pub(crate) vendor_price: Option<usize>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct WorldStop {
+    pub(crate) world_id: usize,
+    pub(crate) quantity: usize,
+    pub(crate) cost: usize,
+    pub(crate) listings: Vec<ItemListing>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct PurchasePlan {
+    pub(crate) listings: Vec<ItemListing>,
+    //units bought
+    pub(crate) quantity: usize,
+    //how many of those units are HQ
+    pub(crate) hq_quantity: usize,
+    pub(crate) total_cost: usize,
+    //units bought past the requested amount
+    pub(crate) surplus: usize,
+    pub(crate) surplus_value: f32,
+    //world_penalty for every world visited besides the home world
+    pub(crate) travel_cost: usize,
+    //total_cost less surplus_value, plus travel_cost
+    pub(crate) net_cost: f32,
+    //false when the listings can't cover the requested amount
+    pub(crate) fulfilled: bool,
+    //the same listings grouped by world, in the order to visit them
+    pub(crate) route: Vec<WorldStop>,
+    //listings_version of the listings the plan was picked from
+    #[serde(default)]
+    pub(crate) listings_version: u64,
+    //unix seconds those listings were fetched from universalis, None if none were
+    #[serde(default)]
+    pub(crate) fetched_at: Option<u64>,
+}
+
+//unix seconds of something fetched `age` ago
+pub(crate) fn fetched_at(age: Duration) -> u64 {
+    SystemTime::now()
+        .checked_sub(age)
+        .unwrap_or(UNIX_EPOCH)
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl PlanOptions {
+    //what the item data says about the item being bought: items that don't come in HQ
+    //have nothing to search for, and vendor stock joins the listings
+    pub(crate) fn for_item(mut self, item: Option<&Item>) -> Self {
+        if let Some(item) = item {
+            if !item.can_be_hq {
+                self.hq = HqMode::Any;
+            }
+            self.vendor_price = item.vendor_offer();
+        }
+        self
+    }
+
+    pub(crate) fn key(&self) -> String {
+        let travel = format!(
+            "{}-{:?}-{:?}-{:?}-{:?}",
+            self.travel.world_penalty,
+            self.travel.max_worlds,
+            self.travel.home_world,
+            self.travel.worlds,
+            self.vendor_price
+        );
+        match self.leftovers {
+            None => format!("{:?}-{travel}", self.hq),
+            Some(e) => format!(
+                "{:?}-{travel}-{:?}-{:?}-{}",
+                self.hq, e.kind, e.quality, e.n
+            ),
+        }
+    }
+}
+
+impl TravelOptions {
+    pub(crate) fn is_active(&self) -> bool {
+        self.world_penalty > 0 || self.max_worlds.is_some()
+    }
+
+    pub(crate) fn penalty_for(&self, route: &[WorldStop]) -> usize {
+        let away = route
+            .iter()
+            .filter(|s| Some(s.world_id) != self.home_world && s.world_id != VENDOR_WORLD)
+            .count();
+        away * self.world_penalty
+    }
+}
+
+impl PurchasePlan {
+    pub(crate) fn new(listings: Vec<ItemListing>, amount: usize, leftover_value: f32) -> Self {
+        let quantity = listings.iter().map(|l| l.quantity).sum();
+        let hq_quantity = listings.iter().filter(|l| l.hq).map(|l| l.quantity).sum();
+        let total_cost = listings.iter().map(|l| l.total_price).sum();
+        let surplus = quantity - amount.min(quantity);
+        let surplus_value = surplus as f32 * leftover_value;
+        Self {
+            route: Vec::new(),
+            listings,
+            quantity,
+            hq_quantity,
+            total_cost,
+            surplus,
+            surplus_value,
+            travel_cost: 0,
+            net_cost: total_cost as f32 - surplus_value,
+            fulfilled: quantity >= amount,
+            listings_version: 0,
+            fetched_at: None,
+        }
+    }
+
+    //adds the stops to visit and the travel penalty for them to the plan
+    pub(crate) fn routed(mut self, travel: &TravelOptions) -> Self {
+        self.route = plan_route(&self.listings, travel);
+        self.net_cost -= self.travel_cost as f32;
+        self.travel_cost = travel.penalty_for(&self.route);
+        self.net_cost += self.travel_cost as f32;
+        self
+    }
+}
+
+//group listings by world: home world first, then the biggest spend
+pub(crate) fn plan_route(listings: &[ItemListing], travel: &TravelOptions) -> Vec<WorldStop> {
+    let mut route: Vec<WorldStop> = Vec::new();
+    for listing in listings {
+        match route.iter_mut().find(|s| s.world_id == listing.world_id) {
+            Some(stop) => {
+                stop.quantity += listing.quantity;
+                stop.cost += listing.total_price;
+                stop.listings.push(listing.clone());
+            }
+            None => route.push(WorldStop {
+                world_id: listing.world_id,
+                quantity: listing.quantity,
+                cost: listing.total_price,
+                listings: vec![listing.clone()],
+            }),
+        }
+    }
+    route.sort_by_key(|s| (Some(s.world_id) != travel.home_world, Reverse(s.cost)));
+    route
 }
 
 //response from https://universalis.app/api/v2/{{world}}/{{itemid}}
@@ -34,9 +315,12 @@ pub(crate) struct UniversalisMbCurrent {
     // currentAveragePrice: f32,
     // currentAveragePriceNQ: f32,
     // currentAveragePriceHQ: f32,
-    // regularSaleVelocity: f32,
-    // nqSaleVelocity: f32,
-    // hqSaleVelocity: f32,
+    #[serde(default)]
+    regularSaleVelocity: f32,
+    #[serde(default)]
+    nqSaleVelocity: f32,
+    #[serde(default)]
+    hqSaleVelocity: f32,
     // averagePrice: f32,
     // averagePriceNQ: f32,
     // averagePriceHQ: f32,
@@ -48,10 +332,21 @@ pub(crate) struct UniversalisMbCurrent {
     // stackSizeHistogramHQ
     // worldName: String,
     // listingsCount: usize,
+    #[serde(default)]
+    recentHistory: Vec<UniversalisMbSale>,
     // recentHistoryCount: usize,
     // unitsForSale: usize,
     // unitsSold: usize,
 }
+//response from https://universalis.app/api/v2/{{world}}/{{itemid}},{{itemid}},... when asking for more than one item
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct UniversalisMbMulti {
+    items: HashMap<String, UniversalisMbCurrent>,
+    #[serde(default)]
+    unresolvedItems: Vec<usize>,
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(non_snake_case)]
 struct UniversalisMbListing {
@@ -63,78 +358,414 @@ struct UniversalisMbListing {
     total: usize,
 }
 
+//response from https://universalis.app/api/v2/history/{{world}}/{{itemid}}
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+pub(crate) struct UniversalisMbHistory {
+    itemID: usize,
+    worldID: Option<usize>,
+    #[serde(default)]
+    regularSaleVelocity: f32,
+    #[serde(default)]
+    nqSaleVelocity: f32,
+    #[serde(default)]
+    hqSaleVelocity: f32,
+    entries: Vec<UniversalisMbSale>,
+}
+
+#[derive(Deserialize, Debug)]
+#[allow(non_snake_case)]
+struct UniversalisMbSale {
+    worldID: Option<usize>,
+    pricePerUnit: f32,
+    quantity: usize,
+    hq: bool,
+    timestamp: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct Sale {
+    world_id: usize,
+    pub(crate) price_per_unit: f32,
+    pub(crate) quantity: usize,
+    pub(crate) hq: bool,
+    //unix seconds
+    pub(crate) timestamp: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct SaleHistory {
+    item_id: usize,
+    //units sold per day
+    pub(crate) regular_sale_velocity: f32,
+    pub(crate) nq_sale_velocity: f32,
+    pub(crate) hq_sale_velocity: f32,
+    //newest first
+    pub(crate) sales: Vec<Sale>,
+}
+
+impl SaleHistory {
+    pub(crate) fn velocity(&self, quality: Quality) -> f32 {
+        match quality {
+            Quality::Any => self.regular_sale_velocity,
+            Quality::Nq => self.nq_sale_velocity,
+            Quality::Hq => self.hq_sale_velocity,
+        }
+    }
+}
+
+fn to_sales(world_id: Option<usize>, entries: Vec<UniversalisMbSale>) -> Vec<Sale> {
+    entries
+        .into_iter()
+        .map(|s| Sale {
+            world_id: world_id.or(s.worldID).unwrap_or_default(),
+            price_per_unit: s.pricePerUnit,
+            quantity: s.quantity,
+            hq: s.hq,
+            timestamp: s.timestamp,
+        })
+        .collect()
+}
+
+impl UniversalisMbHistory {
+    pub(crate) fn into_history(self) -> SaleHistory {
+        SaleHistory {
+            item_id: self.itemID,
+            regular_sale_velocity: self.regularSaleVelocity,
+            nq_sale_velocity: self.nqSaleVelocity,
+            hq_sale_velocity: self.hqSaleVelocity,
+            sales: to_sales(self.worldID, self.entries),
+        }
+    }
+}
+
 impl UniversalisMbCurrent {
-    pub(crate) fn to_listings(self) -> Vec<ItemListing> {
-        let mut v = Vec::new();
-        for l in self.listings {
-            let id = match self.worldID {
-                None => l.worldID.unwrap(),
-                Some(i) => i,
-            };
-
-            v.push(ItemListing {
-                hq: l.hq,
-                item_id: self.itemID,
-                world_id: id,
-                price_per_unit: l.pricePerUnit,
-                quantity: l.quantity,
-                total_price: l.total,
-                retainer_name: l.retainerName,
-            });
-        }
-        v
+    //velocity and recent sales come along with the listings, this leaves the listings in place
+    pub(crate) fn take_history(&mut self) -> SaleHistory {
+        SaleHistory {
+            item_id: self.itemID,
+            regular_sale_velocity: self.regularSaleVelocity,
+            nq_sale_velocity: self.nqSaleVelocity,
+            hq_sale_velocity: self.hqSaleVelocity,
+            sales: to_sales(self.worldID, std::mem::take(&mut self.recentHistory)),
+        }
+    }
+
+    pub(crate) fn into_listings(self) -> Vec<ItemListing> {
+        to_listings(self.itemID, self.worldID, self.listings)
     }
 }
+
+fn to_listings(
+    item_id: usize,
+    world_id: Option<usize>,
+    listings: Vec<UniversalisMbListing>,
+) -> Vec<ItemListing> {
+    let mut v = Vec::new();
+    for l in listings {
+        //data center and region responses name the world per listing
+        let Some(id) = world_id.or(l.worldID) else {
+            trace!("skipping listing without a world for item {item_id}");
+            continue;
+        };
+
+        v.push(ItemListing {
+            hq: l.hq,
+            item_id,
+            world_id: id,
+            price_per_unit: l.pricePerUnit,
+            quantity: l.quantity,
+            total_price: l.total,
+            retainer_name: l.retainerName,
+            vendor: false,
+        });
+    }
+    v
+}
+
+//anything universalis doesn't know answers 404, which would read as the item having no data.
+//it also keeps anything but known names out of the url path
+pub(crate) fn check_location(location: &str) -> Result<(), MarketError> {
+    match locations::is_known(location) {
+        true => Ok(()),
+        false => Err(MarketError::InvalidLocation(location.to_string())),
+    }
+}
+
 pub(crate) async fn get_universalis_mb_data(
     location: &String,
     item_id: usize,
-) -> Result<UniversalisMbCurrent, Error> {
-    let base_url = env::var("XIVP_UNIVERSALIS_API").expect("Missing Env var: XIVP_UNIVERSALIS_API");
-    //let base_url = "https://universalis.app/api/v2/";
+) -> Result<UniversalisMbCurrent, MarketError> {
+    check_location(location)?;
     trace!("getting universalis data for {item_id} @ {location}");
-    let client = reqwest::Client::new();
+    client::universalis()
+        .get(
+            &format!("{location}/{item_id}"),
+            &[("entries", history_entries().to_string())],
+            location,
+            Some(item_id),
+        )
+        .await
+}
 
-    let r = client
-        .get(format!("{base_url}/{location}/{item_id}"))
-        .send()
+//one upstream request for up to MAX_BATCH_SIZE items, ids universalis doesn't know are left out
+async fn get_universalis_mb_batch(
+    location: &String,
+    item_ids: &[usize],
+) -> Result<Vec<UniversalisMbCurrent>, MarketError> {
+    if let [item_id] = item_ids {
+        //a single id gets the single item response shape
+        return match get_universalis_mb_data(location, *item_id).await {
+            Err(MarketError::ItemNotFound(_)) => Ok(Vec::new()),
+            r => r.map(|data| vec![data]),
+        };
+    }
+    check_location(location)?;
+    trace!("getting universalis data for {item_ids:?} @ {location}");
+    let ids = item_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    let data: UniversalisMbMulti = client::universalis()
+        .get(
+            &format!("{location}/{ids}"),
+            &[("entries", history_entries().to_string())],
+            location,
+            None,
+        )
         .await?;
-    match r.status() {
-        StatusCode::OK => Ok(r.json().await?),
-        _ => Err(r
-            .error_for_status()
-            .expect_err("no error when expecting error")),
+    if !data.unresolvedItems.is_empty() {
+        trace!("universalis could not resolve {:?}", data.unresolvedItems);
     }
+    Ok(data.items.into_values().collect())
+}
+
+pub(crate) async fn get_universalis_history_data(
+    location: &String,
+    item_id: usize,
+) -> Result<UniversalisMbHistory, MarketError> {
+    check_location(location)?;
+    trace!("getting universalis history for {item_id} @ {location}");
+    client::universalis()
+        .get(
+            &format!("history/{location}/{item_id}"),
+            &[("entriesToReturn", history_entries().to_string())],
+            location,
+            Some(item_id),
+        )
+        .await
+}
+
+//most item ids universalis takes in one request
+const MAX_BATCH_SIZE: usize = 100;
+
+//number of past sales to request from universalis
+fn history_entries() -> usize {
+    env::var("XIVP_HISTORY_ENTRIES")
+        .unwrap_or(String::from("20"))
+        .parse()
+        .unwrap_or(20)
+}
+
+//listings and the sales history that comes with them for many items from universalis, into the cache.
+//MAX_BATCH_SIZE ids per request, ids universalis has no data for are left out
+async fn fetch_items_listings(
+    world: &String,
+    item_ids: &[usize],
+    cache: &Arc<dyn MarketCache>,
+) -> Result<HashMap<usize, (Vec<ItemListing>, SaleHistory)>, MarketError> {
+    let batches = join_all(
+        item_ids
+            .chunks(MAX_BATCH_SIZE)
+            .map(|chunk| get_universalis_mb_batch(world, chunk)),
+    )
+    .await;
+    let mut found = HashMap::new();
+    for batch in batches {
+        for mut data in batch? {
+            let item_id = data.itemID;
+            let history = data.take_history();
+            cache.set_history(item_id, world.clone(), history.clone());
+            let data = data.into_listings();
+            cache.set_listing(item_id, world.clone(), data.clone());
+            found.insert(item_id, (data, history));
+        }
+    }
+    Ok(found)
+}
+
+//stale listings are served as they are, this gets them refreshed without anyone waiting on it
+fn refresh_in_background(world: &str, item_ids: Vec<usize>, cache: &Arc<dyn MarketCache>) {
+    let item_ids: Vec<usize> = item_ids
+        .into_iter()
+        .filter(|id| cache.start_refresh(*id, world))
+        .collect();
+    if item_ids.is_empty() {
+        return;
+    }
+    let (world, cache) = (world.to_string(), cache.clone());
+    tokio::spawn(async move {
+        trace!("refreshing stale listings {item_ids:?} @ {world}");
+        if let Err(e) = fetch_items_listings(&world, &item_ids, &cache).await {
+            warn!("failed to refresh listings {item_ids:?} @ {world}: {e}");
+        }
+        for item_id in item_ids {
+            cache.finish_refresh(item_id, &world);
+        }
+    });
 }
 
 pub(crate) async fn get_item_listings(
     world: &String,
     item_id: usize,
-    cache: &InMemoryCache,
-) -> Vec<ItemListing> {
+    cache: &Arc<dyn MarketCache>,
+) -> Result<Cached<Vec<ItemListing>>, MarketError> {
     if let Some(v) = cache.get_listing(item_id, world.clone()) {
-        v
+        if v.stale {
+            refresh_in_background(world, vec![item_id], cache);
+        }
+        Ok(v)
     } else {
-        let data = get_universalis_mb_data(world, item_id)
-            .await
-            .unwrap()
-            .to_listings();
+        let mut data = get_universalis_mb_data(world, item_id).await?;
+        cache.set_history(item_id, world.clone(), data.take_history());
+        let data = data.into_listings();
         cache.set_listing(item_id, world.clone(), data.clone());
-        data
+        Ok(Cached {
+            data,
+            age: Duration::ZERO,
+            stale: false,
+        })
+    }
+}
+
+//listings for many items at once: cache hits are served as is, the misses are fetched together.
+//items universalis has no data for come back with no listings
+pub(crate) async fn get_items_listings(
+    world: &String,
+    item_ids: &[usize],
+    cache: &Arc<dyn MarketCache>,
+) -> Result<HashMap<usize, Cached<Vec<ItemListing>>>, MarketError> {
+    let mut found = HashMap::new();
+    let mut missing = Vec::new();
+    let mut stale = Vec::new();
+    for item_id in item_ids {
+        if found.contains_key(item_id) || missing.contains(item_id) {
+            continue;
+        }
+        match cache.get_listing(*item_id, world.clone()) {
+            Some(v) => {
+                if v.stale {
+                    stale.push(*item_id);
+                }
+                found.insert(*item_id, v);
+            }
+            None => missing.push(*item_id),
+        }
+    }
+    if !stale.is_empty() {
+        refresh_in_background(world, stale, cache);
+    }
+
+    let mut fetched = fetch_items_listings(world, &missing, cache).await?;
+    for item_id in missing {
+        let data = fetched
+            .remove(&item_id)
+            .map(|(listings, _)| listings)
+            .unwrap_or_default();
+        found.insert(
+            item_id,
+            Cached {
+                data,
+                age: Duration::ZERO,
+                stale: false,
+            },
+        );
+    }
+    Ok(found)
+}
+
+//sales history for many items at once. it comes along with listings and is kept as long as they are,
+//so misses are fetched the same way. items universalis has no data for are left out
+pub(crate) async fn get_items_history(
+    world: &String,
+    item_ids: &[usize],
+    cache: &Arc<dyn MarketCache>,
+) -> Result<HashMap<usize, SaleHistory>, MarketError> {
+    let mut found = HashMap::new();
+    let mut missing = Vec::new();
+    let mut stale = Vec::new();
+    for item_id in item_ids {
+        if found.contains_key(item_id) || missing.contains(item_id) {
+            continue;
+        }
+        match cache.get_history(*item_id, world.clone()) {
+            Some(h) => {
+                if h.stale {
+                    stale.push(*item_id);
+                }
+                found.insert(*item_id, h.data);
+            }
+            None => missing.push(*item_id),
+        }
+    }
+    if !stale.is_empty() {
+        refresh_in_background(world, stale, cache);
+    }
+    let fetched = fetch_items_listings(world, &missing, cache).await?;
+    found.extend(
+        fetched
+            .into_iter()
+            .map(|(item_id, (_, history))| (item_id, history)),
+    );
+    Ok(found)
+}
+
+pub(crate) async fn get_sale_history(
+    world: &String,
+    item_id: usize,
+    cache: &Arc<dyn MarketCache>,
+) -> Result<SaleHistory, MarketError> {
+    if let Some(v) = cache.get_history(item_id, world.clone()) {
+        if v.stale {
+            refresh_in_background(world, vec![item_id], cache);
+        }
+        Ok(v.data)
+    } else {
+        let data = get_universalis_history_data(world, item_id)
+            .await?
+            .into_history();
+        cache.set_history(item_id, world.clone(), data.clone());
+        Ok(data)
     }
 }
 
+//estimated price per unit the item sells for, 0 if there is nothing to estimate from
+pub(crate) async fn get_sale_price(
+    location: &String,
+    item_id: usize,
+    estimate: SalePriceEstimate,
+    cache: &Arc<dyn MarketCache>,
+) -> Result<f32, MarketError> {
+    let listings = get_item_listings(location, item_id, cache).await?.data;
+    let sales = match estimate.kind {
+        EstimatorKind::History => get_sale_history(location, item_id, cache).await?.sales,
+        _ => Vec::new(),
+    };
+    Ok(estimate.estimate(&listings, &sales).unwrap_or(0.0))
+}
+
 //only allow 1 thread to run optimizer::get_cheapest_combination for a set of arguments at a time, all others should just wait for that one and return the same result
 pub(crate) async fn get_cheapest_combination(
     item_id: usize,
     location: String,
-    cache: &Arc<InMemoryCache>,
+    cache: &Arc<dyn MarketCache>,
     amount: usize,
-    hq: bool,
-    running_jobs: &Arc<Mutex<HashMap<String, Shared<BoxFuture<'static, Vec<ItemListing>>>>>>,
-) -> Vec<ItemListing> {
-
+    options: PlanOptions,
+    running_jobs: &RunningJobs,
+) -> Result<PurchasePlan, MarketError> {
     let mut running = running_jobs.lock().await;
-    let id = format!("cheapest-{location}-{item_id}-{amount}-{hq}");
+    let id = format!("cheapest-{location}-{item_id}-{amount}-{}", options.key());
     if let Some(r) = running.get(&id) {
         //another thread is running this
         return r.clone().await;
@@ -143,9 +774,10 @@ pub(crate) async fn get_cheapest_combination(
     let c = cache.clone();
     let l = location.clone();
     //another thread is not running, and we should run
-    let fut = async move { optimizer::get_cheapest_combination(item_id, l, &c, amount, hq).await }
-        .boxed()
-        .shared();
+    let fut =
+        async move { optimizer::get_cheapest_combination(item_id, l, &c, amount, options).await }
+            .boxed()
+            .shared();
     running.insert(id.clone(), fut.clone());
     drop(running); //release mutex
 
@@ -0,0 +1,214 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, sync::Arc};
+
+use crate::{
+    cache::MarketCache,
+    crafting::Item,
+    market::{
+        estimator::{Quality, SalePriceEstimate},
+        get_items_history, get_items_listings,
+        tax::{Revenue, TaxModel},
+        ItemListing, MarketError, Sale, SaleHistory,
+    },
+};
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ArbitrageSort {
+    //profit per unit after tax
+    #[default]
+    Profit,
+    //units sold per day
+    Velocity,
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "from", rename_all = "snake_case")]
+pub(crate) enum Source {
+    //an NPC gil shop, any quantity
+    Vendor,
+    //the cheapest listing of that quality in the data center
+    World { world_id: usize, quantity: usize },
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct Opportunity {
+    pub(crate) item_id: usize,
+    pub(crate) name: String,
+    pub(crate) hq: bool,
+    pub(crate) buy: Source,
+    //everything below is per unit
+    pub(crate) buy_price: f32,
+    //none from vendors
+    pub(crate) purchase_tax: f32,
+    //None to sell anywhere at the location
+    pub(crate) sell_world: Option<usize>,
+    pub(crate) revenue: Revenue,
+    pub(crate) profit: f32,
+    //units of this quality sold per day at the location
+    pub(crate) sale_velocity: f32,
+}
+
+//buy low, sell high: gil shop items that sell for more on the market board, and listings on one world
+//priced below what the item sells for on another world of the same data center.
+//`estimate` is how sale prices are judged, its quality is ignored since each quality is checked on its own
+pub(crate) async fn find_opportunities(
+    location: &String,
+    items: &[&Item],
+    estimate: SalePriceEstimate,
+    tax: TaxModel,
+    sort: ArbitrageSort,
+    cache: &Arc<dyn MarketCache>,
+) -> Result<Vec<Opportunity>, MarketError> {
+    let item_ids: Vec<usize> = items.iter().map(|i| i.id).collect();
+    let listings = get_items_listings(location, &item_ids, cache).await?;
+    //written along with the listings just read, so these are nearly always cache hits
+    let histories = get_items_history(location, &item_ids, cache).await?;
+
+    let mut found = Vec::new();
+    for item in items {
+        //no market data, nothing to compare against
+        let Some(history) = histories.get(&item.id) else {
+            continue;
+        };
+        let listings = listings
+            .get(&item.id)
+            .map(|l| l.data.as_slice())
+            .unwrap_or_default();
+        found.extend(vendor_flip(item, listings, history, estimate, tax));
+        found.extend(world_spread(item, listings, history, estimate, tax));
+    }
+    found.sort_by(|a, b| match sort {
+        ArbitrageSort::Profit => b
+            .profit
+            .total_cmp(&a.profit)
+            .then(b.sale_velocity.total_cmp(&a.sale_velocity)),
+        ArbitrageSort::Velocity => b
+            .sale_velocity
+            .total_cmp(&a.sale_velocity)
+            .then(b.profit.total_cmp(&a.profit)),
+    });
+    Ok(found)
+}
+
+//None unless it's worth doing after tax
+#[allow(clippy::too_many_arguments)]
+fn opportunity(
+    item: &Item,
+    hq: bool,
+    buy: Source,
+    buy_price: f32,
+    sell_price: f32,
+    sell_world: Option<usize>,
+    sale_velocity: f32,
+    tax: TaxModel,
+) -> Option<Opportunity> {
+    let purchase_tax = match buy {
+        Source::Vendor => 0.0,
+        Source::World { .. } => buy_price * tax.purchase_rate,
+    };
+    let revenue = tax.revenue(sell_price);
+    let profit = revenue.net - buy_price - purchase_tax;
+    (profit > 0.0).then(|| Opportunity {
+        item_id: item.id,
+        name: item.name.clone(),
+        hq,
+        buy,
+        buy_price,
+        purchase_tax,
+        sell_world,
+        revenue,
+        profit,
+        sale_velocity,
+    })
+}
+
+//vendors only sell NQ
+fn vendor_flip(
+    item: &Item,
+    listings: &[ItemListing],
+    history: &SaleHistory,
+    estimate: SalePriceEstimate,
+    tax: TaxModel,
+) -> Option<Opportunity> {
+    let price = item.vendor_offer()?;
+    let estimate = SalePriceEstimate {
+        quality: Quality::Nq,
+        ..estimate
+    };
+    let sell_price = estimate.estimate(listings, &history.sales)?;
+    opportunity(
+        item,
+        false,
+        Source::Vendor,
+        price as f32,
+        sell_price,
+        None,
+        history.velocity(Quality::Nq),
+        tax,
+    )
+}
+
+//the cheapest listing of each quality against the world it would sell for the most on
+fn world_spread(
+    item: &Item,
+    listings: &[ItemListing],
+    history: &SaleHistory,
+    estimate: SalePriceEstimate,
+    tax: TaxModel,
+) -> Vec<Opportunity> {
+    let mut found = Vec::new();
+    for (hq, quality) in [(false, Quality::Nq), (true, Quality::Hq)] {
+        let Some(cheapest) = listings
+            .iter()
+            .filter(|l| l.hq == hq)
+            .min_by(|a, b| a.price_per_unit.total_cmp(&b.price_per_unit))
+        else {
+            continue;
+        };
+        let estimate = SalePriceEstimate {
+            quality,
+            ..estimate
+        };
+        let worlds: BTreeSet<usize> = listings
+            .iter()
+            .map(|l| l.world_id)
+            .chain(history.sales.iter().map(|s| s.world_id))
+            .filter(|w| *w != cheapest.world_id)
+            .collect();
+        let best = worlds
+            .into_iter()
+            .filter_map(|world| {
+                let listings: Vec<ItemListing> = listings
+                    .iter()
+                    .filter(|l| l.world_id == world)
+                    .cloned()
+                    .collect();
+                let sales: Vec<Sale> = history
+                    .sales
+                    .iter()
+                    .filter(|s| s.world_id == world)
+                    .cloned()
+                    .collect();
+                estimate.estimate(&listings, &sales).map(|p| (world, p))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        let Some((world, sell_price)) = best else {
+            continue;
+        };
+        found.extend(opportunity(
+            item,
+            hq,
+            Source::World {
+                world_id: cheapest.world_id,
+                quantity: cheapest.quantity,
+            },
+            cheapest.price_per_unit,
+            sell_price,
+            Some(world),
+            history.velocity(quality),
+            tax,
+        ));
+    }
+    found
+}
@@ -0,0 +1,180 @@
+use log::{trace, warn};
+use reqwest::{header, StatusCode};
+use serde::de::DeserializeOwned;
+use std::{
+    env,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::{sync::Semaphore, time::sleep};
+
+use crate::market::MarketError;
+
+//first retry waits this long, every retry after that twice as long as the one before
+const BACKOFF_BASE: Duration = Duration::from_millis(500);
+const BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+//one client for every universalis request, so they share a connection pool and the same limits
+pub(super) fn universalis() -> &'static UniversalisClient {
+    static CLIENT: OnceLock<UniversalisClient> = OnceLock::new();
+    CLIENT.get_or_init(UniversalisClient::from_env)
+}
+
+//token bucket: `rate` requests per second on average, bursts of up to `rate`
+struct Budget {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Budget {
+    //None when a token was taken, otherwise how long until there is one
+    fn take(&mut self) -> Option<Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate.max(1.0));
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+pub(super) struct UniversalisClient {
+    http: reqwest::Client,
+    base_url: String,
+    budget: Mutex<Budget>,
+    //requests in flight at once
+    in_flight: Semaphore,
+    max_retries: u32,
+}
+
+impl UniversalisClient {
+    fn from_env() -> Self {
+        let base_url =
+            env::var("XIVP_UNIVERSALIS_API").expect("Missing Env var: XIVP_UNIVERSALIS_API");
+        let rate: f64 = env::var("XIVP_UNIVERSALIS_RPS")
+            .unwrap_or(String::from("20"))
+            .parse()
+            .unwrap_or(20.0);
+        let concurrency: usize = env::var("XIVP_UNIVERSALIS_CONCURRENCY")
+            .unwrap_or(String::from("8"))
+            .parse()
+            .unwrap_or(8);
+        let max_retries: u32 = env::var("XIVP_UNIVERSALIS_MAX_RETRIES")
+            .unwrap_or(String::from("3"))
+            .parse()
+            .unwrap_or(3);
+        let http = reqwest::Client::builder()
+            .pool_max_idle_per_host(concurrency)
+            .timeout(Duration::from_secs(30))
+            .build()
+            .expect("failed to build universalis client");
+        Self {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            budget: Mutex::new(Budget {
+                rate: rate.max(0.1),
+                tokens: rate.max(1.0),
+                refilled: Instant::now(),
+            }),
+            in_flight: Semaphore::new(concurrency.max(1)),
+            max_retries,
+        }
+    }
+
+    async fn wait_for_budget(&self) {
+        loop {
+            let wait = self.budget.lock().unwrap().take();
+            match wait {
+                None => return,
+                Some(d) => sleep(d).await,
+            }
+        }
+    }
+
+    //GET {base_url}/{path}, retrying 429s, 5xx and connection errors with exponential backoff.
+    //`location` and `item_id` are what a 400 or 404 gets blamed on, see MarketError
+    pub(super) async fn get<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+        location: &str,
+        item_id: Option<usize>,
+    ) -> Result<T, MarketError> {
+        let mut attempt = 0;
+        loop {
+            let (result, retry_after) = self.get_once(path, query, location, item_id).await;
+            let Some(retry_after) = retry_after else {
+                return result;
+            };
+            //longer than a handler should hang for, pass the 429 on so the caller sees the Retry-After
+            if attempt >= self.max_retries || retry_after > BACKOFF_MAX {
+                return result;
+            }
+            let backoff = (BACKOFF_BASE * 2u32.pow(attempt)).min(BACKOFF_MAX);
+            let wait = retry_after.max(backoff).min(BACKOFF_MAX);
+            warn!("universalis request {path} failed, retrying in {wait:?}");
+            sleep(wait).await;
+            attempt += 1;
+        }
+    }
+
+    //the result, and for failures worth retrying how long universalis asked us to wait (zero if it didn't say)
+    async fn get_once<T: DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+        location: &str,
+        item_id: Option<usize>,
+    ) -> (Result<T, MarketError>, Option<Duration>) {
+        self.wait_for_budget().await;
+        let _permit = self.in_flight.acquire().await.unwrap();
+        trace!("GET {path}");
+        let r = match self
+            .http
+            .get(format!("{}/{path}", self.base_url))
+            .query(query)
+            .send()
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => return (Err(e.into()), Some(Duration::ZERO)),
+        };
+        let error = match r.status() {
+            StatusCode::OK => {
+                //a body that doesn't parse won't parse any better the second time
+                return (r.json().await.map_err(MarketError::from), None);
+            }
+            StatusCode::TOO_MANY_REQUESTS => {
+                let retry_after = r
+                    .headers()
+                    .get(header::RETRY_AFTER)
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse().ok());
+                return (
+                    Err(MarketError::RateLimited { retry_after }),
+                    Some(Duration::from_secs(retry_after.unwrap_or(0))),
+                );
+            }
+            StatusCode::BAD_REQUEST => MarketError::InvalidLocation(location.to_string()),
+            //universalis answers 404 both for unknown items and unknown locations,
+            //without an item to blame (multi item requests list unknown items instead) it's the location
+            StatusCode::NOT_FOUND => match item_id {
+                Some(id) => MarketError::ItemNotFound(id),
+                None => MarketError::InvalidLocation(location.to_string()),
+            },
+            status if status.is_server_error() => {
+                return (
+                    Err(MarketError::Upstream(format!("status {status}"))),
+                    Some(Duration::ZERO),
+                );
+            }
+            status => MarketError::Upstream(format!("status {status}")),
+        };
+        (Err(error), None)
+    }
+}
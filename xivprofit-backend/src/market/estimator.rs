@@ -0,0 +1,94 @@
+use serde::Deserialize;
+
+use crate::market::{ItemListing, Sale};
+
+#[derive(Clone, Copy, Default, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum EstimatorKind {
+    #[default]
+    Lowest,
+    //average of the n cheapest listings
+    LowestN,
+    Median,
+    //median of recent sales
+    History,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Quality {
+    #[default]
+    Any,
+    Nq,
+    Hq,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SalePriceEstimate {
+    pub(crate) kind: EstimatorKind,
+    pub(crate) quality: Quality,
+    //only used by LowestN
+    pub(crate) n: usize,
+}
+
+impl Quality {
+    pub(crate) fn matches(&self, hq: bool) -> bool {
+        match self {
+            Quality::Any => true,
+            Quality::Nq => !hq,
+            Quality::Hq => hq,
+        }
+    }
+}
+
+impl SalePriceEstimate {
+    pub(crate) fn new(
+        kind: Option<EstimatorKind>,
+        quality: Option<Quality>,
+        n: Option<usize>,
+    ) -> Self {
+        Self {
+            kind: kind.unwrap_or_default(),
+            quality: quality.unwrap_or_default(),
+            n: n.unwrap_or(5).max(1),
+        }
+    }
+
+    //None when there is nothing of the requested quality to base an estimate on
+    pub(crate) fn estimate(&self, listings: &[ItemListing], sales: &[Sale]) -> Option<f32> {
+        let mut prices: Vec<f32> = match self.kind {
+            EstimatorKind::History => sales
+                .iter()
+                .filter(|s| self.quality.matches(s.hq))
+                .map(|s| s.price_per_unit)
+                .collect(),
+            _ => listings
+                .iter()
+                .filter(|l| self.quality.matches(l.hq))
+                .map(|l| l.price_per_unit)
+                .collect(),
+        };
+        if prices.is_empty() {
+            return None;
+        }
+        prices.sort_by(|a, b| a.total_cmp(b));
+        match self.kind {
+            EstimatorKind::Lowest => prices.first().copied(),
+            EstimatorKind::LowestN => {
+                let cheapest = &prices[..self.n.min(prices.len())];
+                Some(cheapest.iter().sum::<f32>() / cheapest.len() as f32)
+            }
+            EstimatorKind::Median | EstimatorKind::History => Some(median(&prices)),
+        }
+    }
+}
+
+//prices must be sorted
+fn median(prices: &[f32]) -> f32 {
+    let mid = prices.len() / 2;
+    if prices.len().is_multiple_of(2) {
+        (prices[mid - 1] + prices[mid]) / 2.0
+    } else {
+        prices[mid]
+    }
+}
@@ -0,0 +1,169 @@
+use bson::doc;
+use futures::{SinkExt, StreamExt};
+use log::{info, trace, warn};
+use serde::Deserialize;
+use std::{env, sync::Arc, time::Duration};
+use tokio::time::sleep;
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::{
+    cache::MarketCache,
+    market::{to_listings, ItemListing, UniversalisMbListing},
+};
+
+const RECONNECT_BASE: Duration = Duration::from_secs(1);
+const RECONNECT_MAX: Duration = Duration::from_secs(60);
+
+//messages from the universalis websocket feed, BSON encoded
+#[derive(Deserialize)]
+#[serde(tag = "event")]
+enum FeedEvent {
+    #[serde(rename = "listings/add")]
+    ListingsAdd {
+        item: usize,
+        world: usize,
+        listings: Vec<UniversalisMbListing>,
+    },
+    #[serde(rename = "listings/remove")]
+    ListingsRemove {
+        item: usize,
+        world: usize,
+        listings: Vec<UniversalisMbListing>,
+    },
+    //sales and anything else we didn't subscribe to
+    #[serde(other)]
+    Other,
+}
+
+//cache locations to keep current and the worlds in them, from XIVP_LIVE_LOCATIONS:
+//`Cerberus=80;Chaos=39,71,80,83,85,97,400,401`
+fn locations_from_env() -> Vec<(String, Vec<usize>)> {
+    env::var("XIVP_LIVE_LOCATIONS")
+        .unwrap_or_default()
+        .split(';')
+        .filter_map(|l| {
+            let (location, worlds) = l.split_once('=')?;
+            let worlds: Vec<usize> = worlds
+                .split(',')
+                .filter_map(|w| w.trim().parse().ok())
+                .collect();
+            Some((location.trim().to_string(), worlds))
+        })
+        .filter(|(location, worlds)| !location.is_empty() && !worlds.is_empty())
+        .collect()
+}
+
+//listens to XIVP_UNIVERSALIS_WS (wss://universalis.app/api/ws, or anything speaking the same protocol)
+//and applies listings added and removed on the subscribed worlds to the cached listings.
+//cheapest plans check the listings they came from, so the changed ones are recomputed on their next request.
+//reconnects whenever the feed drops, updates missed meanwhile are caught by the regular refresh
+pub(crate) async fn run_subscriber(cache: Arc<dyn MarketCache>) {
+    let url = env::var("XIVP_UNIVERSALIS_WS").unwrap_or_default();
+    let locations = locations_from_env();
+    if url.is_empty() || locations.is_empty() {
+        info!("XIVP_UNIVERSALIS_WS or XIVP_LIVE_LOCATIONS is empty, listings will only refresh when stale");
+        return;
+    }
+    let mut worlds: Vec<usize> = locations.iter().flat_map(|(_, w)| w.clone()).collect();
+    worlds.sort_unstable();
+    worlds.dedup();
+
+    let mut attempt = 0;
+    loop {
+        match listen(&url, &worlds, &locations, &cache).await {
+            Ok(()) => {
+                info!("universalis feed closed");
+                attempt = 0;
+            }
+            Err(e) => warn!("universalis feed failed: {e}"),
+        }
+        let wait = (RECONNECT_BASE * 2u32.pow(attempt)).min(RECONNECT_MAX);
+        info!("reconnecting to universalis feed in {wait:?}");
+        sleep(wait).await;
+        attempt = (attempt + 1).min(6);
+    }
+}
+
+async fn listen(
+    url: &str,
+    worlds: &[usize],
+    locations: &[(String, Vec<usize>)],
+    cache: &Arc<dyn MarketCache>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let (mut ws, _) = connect_async(url).await?;
+    info!("connected to universalis feed {url}, subscribing to worlds {worlds:?}");
+    for world in worlds {
+        for channel in ["listings/add", "listings/remove"] {
+            let subscribe = doc! {
+                "event": "subscribe",
+                "channel": format!("{channel}{{world={world}}}"),
+            };
+            ws.send(Message::Binary(bson::to_vec(&subscribe)?)).await?;
+        }
+    }
+    while let Some(message) = ws.next().await {
+        match message? {
+            Message::Binary(b) => match bson::from_slice(&b) {
+                Ok(event) => apply(event, locations, cache),
+                Err(e) => warn!("unreadable universalis feed message: {e}"),
+            },
+            Message::Close(_) => break,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn apply(event: FeedEvent, locations: &[(String, Vec<usize>)], cache: &Arc<dyn MarketCache>) {
+    let (item, world, added, removed) = match event {
+        FeedEvent::ListingsAdd {
+            item,
+            world,
+            listings,
+        } => (
+            item,
+            world,
+            to_listings(item, Some(world), listings),
+            Vec::new(),
+        ),
+        FeedEvent::ListingsRemove {
+            item,
+            world,
+            listings,
+        } => (
+            item,
+            world,
+            Vec::new(),
+            to_listings(item, Some(world), listings),
+        ),
+        FeedEvent::Other => return,
+    };
+    for (location, _) in locations.iter().filter(|(_, w)| w.contains(&world)) {
+        let updated = cache.update_listing(item, location, &mut |current| {
+            apply_changes(current, &added, &removed)
+        });
+        if updated {
+            trace!(
+                "applied {} added and {} removed listings of item {item} on world {world} to {location}",
+                added.len(),
+                removed.len()
+            );
+        }
+    }
+}
+
+fn apply_changes(current: &mut Vec<ItemListing>, added: &[ItemListing], removed: &[ItemListing]) {
+    for listing in removed {
+        if let Some(i) = current.iter().position(|l| l == listing) {
+            current.remove(i);
+        }
+    }
+    for listing in added {
+        //a listing we already have means the cached entry was fetched after it went up
+        if !current.contains(listing) {
+            current.push(listing.clone());
+        }
+    }
+    //universalis sorts by unit price, keep it that way
+    current.sort_by(|a, b| a.price_per_unit.total_cmp(&b.price_per_unit));
+}
@@ -0,0 +1,174 @@
+use std::env;
+
+//regions, data centers and worlds universalis knows, kept in step with the frontend's data.ts
+const REGIONS: &[&str] = &[
+    "Japan",
+    "North-America",
+    "Europe",
+    "Oceania",
+    "NA-Cloud-DC",
+    "中国",
+    "한국",
+];
+
+const DATA_CENTERS: &[&str] = &[
+    "Elemental",
+    "Gaia",
+    "Mana",
+    "Aether",
+    "Primal",
+    "Chaos",
+    "Light",
+    "Crystal",
+    "Materia",
+    "Meteor",
+    "Dynamis",
+    "NA Cloud DC (Beta)",
+    "陆行鸟",
+    "莫古力",
+    "猫小胖",
+    "豆豆柴",
+    "한국",
+];
+
+//(id, name)
+const WORLDS: &[(usize, &str)] = &[
+    (21, "Ravana"),
+    (22, "Bismarck"),
+    (23, "Asura"),
+    (24, "Belias"),
+    (28, "Pandaemonium"),
+    (29, "Shinryu"),
+    (30, "Unicorn"),
+    (31, "Yojimbo"),
+    (32, "Zeromus"),
+    (33, "Twintania"),
+    (34, "Brynhildr"),
+    (35, "Famfrit"),
+    (36, "Lich"),
+    (37, "Mateus"),
+    (39, "Omega"),
+    (40, "Jenova"),
+    (41, "Zalera"),
+    (42, "Zodiark"),
+    (43, "Alexander"),
+    (44, "Anima"),
+    (45, "Carbuncle"),
+    (46, "Fenrir"),
+    (47, "Hades"),
+    (48, "Ixion"),
+    (49, "Kujata"),
+    (50, "Typhon"),
+    (51, "Ultima"),
+    (52, "Valefor"),
+    (53, "Exodus"),
+    (54, "Faerie"),
+    (55, "Lamia"),
+    (56, "Phoenix"),
+    (57, "Siren"),
+    (58, "Garuda"),
+    (59, "Ifrit"),
+    (60, "Ramuh"),
+    (61, "Titan"),
+    (62, "Diabolos"),
+    (63, "Gilgamesh"),
+    (64, "Leviathan"),
+    (65, "Midgardsormr"),
+    (66, "Odin"),
+    (67, "Shiva"),
+    (68, "Atomos"),
+    (69, "Bahamut"),
+    (70, "Chocobo"),
+    (71, "Moogle"),
+    (72, "Tonberry"),
+    (73, "Adamantoise"),
+    (74, "Coeurl"),
+    (75, "Malboro"),
+    (76, "Tiamat"),
+    (77, "Ultros"),
+    (78, "Behemoth"),
+    (79, "Cactuar"),
+    (80, "Cerberus"),
+    (81, "Goblin"),
+    (82, "Mandragora"),
+    (83, "Louisoix"),
+    (85, "Spriggan"),
+    (86, "Sephirot"),
+    (87, "Sophia"),
+    (88, "Zurvan"),
+    (90, "Aegis"),
+    (91, "Balmung"),
+    (92, "Durandal"),
+    (93, "Excalibur"),
+    (94, "Gungnir"),
+    (95, "Hyperion"),
+    (96, "Masamune"),
+    (97, "Ragnarok"),
+    (98, "Ridill"),
+    (99, "Sargatanas"),
+    (400, "Sagittarius"),
+    (401, "Phantom"),
+    (402, "Alpha"),
+    (403, "Raiden"),
+    (404, "Marilith"),
+    (405, "Seraph"),
+    (406, "Halicarnassus"),
+    (407, "Maduin"),
+    (408, "Cuchulainn"),
+    (409, "Kraken"),
+    (410, "Rafflesia"),
+    (411, "Golem"),
+    (3000, "Cloudtest01"),
+    (3001, "Cloudtest02"),
+    (1167, "红玉海"),
+    (1081, "神意之地"),
+    (1042, "拉诺西亚"),
+    (1044, "幻影群岛"),
+    (1060, "萌芽池"),
+    (1173, "宇宙和音"),
+    (1174, "沃仙曦染"),
+    (1175, "晨曦王座"),
+    (1172, "白银乡"),
+    (1076, "白金幻象"),
+    (1171, "神拳痕"),
+    (1170, "潮风亭"),
+    (1113, "旅人栈桥"),
+    (1121, "拂晓之间"),
+    (1166, "龙巢神殿"),
+    (1176, "梦羽宝境"),
+    (1043, "紫水栈桥"),
+    (1169, "延夏"),
+    (1106, "静语庄园"),
+    (1045, "摩杜纳"),
+    (1177, "海猫茶屋"),
+    (1178, "柔风海湾"),
+    (1179, "琥珀原"),
+    (1192, "水晶塔"),
+    (1183, "银泪湖"),
+    (1180, "太阳海岸"),
+    (1186, "伊修加德"),
+    (1201, "红茶川"),
+    (1068, "黄金谷"),
+    (1064, "月牙湾"),
+    (1187, "雪松原"),
+    (2075, "카벙클"),
+    (2076, "초코보"),
+    (2077, "모그리"),
+    (2078, "톤베리"),
+    (2080, "펜리르"),
+];
+
+//a region, data center or world by name in any case, or a world by id.
+//XIVP_EXTRA_LOCATIONS (comma separated) covers anything newer than the lists above
+pub(crate) fn is_known(location: &str) -> bool {
+    let named = |name: &&str| name.eq_ignore_ascii_case(location);
+    REGIONS.iter().any(named)
+        || DATA_CENTERS.iter().any(named)
+        || WORLDS
+            .iter()
+            .any(|(id, name)| named(name) || id.to_string() == location)
+        || env::var("XIVP_EXTRA_LOCATIONS")
+            .unwrap_or_default()
+            .split(',')
+            .any(|l| !l.trim().is_empty() && l.trim().eq_ignore_ascii_case(location))
+}
@@ -1,115 +1,428 @@
 use crate::{
-    cache::InMemoryCache,
-    market::{get_item_listings, ItemListing},
+    cache::MarketCache,
+    market::{
+        fetched_at, get_item_listings, get_sale_price, listings_version, route, HqMode,
+        ItemListing, MarketError, PlanOptions, PurchasePlan,
+    },
 };
-use itertools::Itertools;
 use log::trace;
-use std::time::SystemTime;
+use std::{sync::Arc, time::SystemTime};
+
+//0/1 knapsack over exact quantities: the cheapest way to buy exactly q units, for every q up to a bound
+struct Knapsack {
+    //best[q]: (total price, listing count), ties go to fewer listings
+    best: Vec<Option<(usize, usize)>>,
+    //taken[i][q]: listing i is part of the cheapest way to reach q using listings 0..=i
+    taken: Vec<Vec<bool>>,
+}
+
+impl Knapsack {
+    fn new(listings: &[ItemListing], max_quantity: usize) -> Self {
+        let mut best = vec![None; max_quantity + 1];
+        best[0] = Some((0, 0));
+        let mut taken = Vec::with_capacity(listings.len());
+        for listing in listings {
+            let mut row = vec![false; max_quantity + 1];
+            if listing.quantity > 0 && listing.quantity <= max_quantity {
+                //walk down so best[q - quantity] never already includes this listing
+                for q in (listing.quantity..=max_quantity).rev() {
+                    let Some((cost, count)) = best[q - listing.quantity] else {
+                        continue;
+                    };
+                    let candidate = (cost + listing.total_price, count + 1);
+                    if best[q].is_none_or(|b| candidate < b) {
+                        best[q] = Some(candidate);
+                        row[q] = true;
+                    }
+                }
+            }
+            taken.push(row);
+        }
+        Self { best, taken }
+    }
+
+    fn pick(&self, listings: &[ItemListing], mut quantity: usize) -> Vec<ItemListing> {
+        let mut chosen = Vec::new();
+        for (i, row) in self.taken.iter().enumerate().rev() {
+            if row[quantity] {
+                chosen.push(listings[i].clone());
+                quantity -= listings[i].quantity;
+            }
+        }
+        chosen.reverse();
+        chosen
+    }
+}
+
+//an unlimited vendor offer as listings of 1, 2, 4, ... units, so every quantity up to `amount` is a subset of them
+fn vendor_listings(item_id: usize, price: usize, amount: usize) -> Vec<ItemListing> {
+    let mut listings = Vec::new();
+    let (mut size, mut left) = (1, amount);
+    while left > 0 {
+        let quantity = size.min(left);
+        listings.push(ItemListing::vendor(item_id, price, quantity));
+        left -= quantity;
+        size *= 2;
+    }
+    listings
+}
+
+//whatever vendor pieces were picked are one purchase
+fn merge_vendor_listings(listings: Vec<ItemListing>) -> Vec<ItemListing> {
+    let (vendor, mut market): (Vec<ItemListing>, Vec<ItemListing>) =
+        listings.into_iter().partition(|l| l.vendor);
+    if let Some(first) = vendor.first() {
+        let quantity = vendor.iter().map(|l| l.quantity).sum();
+        market.push(ItemListing::vendor(
+            first.item_id,
+            first.price_per_unit as usize,
+            quantity,
+        ));
+    }
+    market
+}
+
+//a surplus unit is credited no more than the cheapest unit on offer cost, so overbuying to resell never looks
+//better than free. which units end up surplus isn't tracked, so the cheapest one stands in for all of them
+fn credited_leftover_value(listings: &[ItemListing], leftover_value: f32) -> f32 {
+    listings
+        .iter()
+        .map(|l| l.price_per_unit)
+        .min_by(|a, b| a.total_cmp(b))
+        .map_or(0.0, |p| leftover_value.min(p))
+}
+
+//min-cost cover: the set of listings adding up to at least `amount` with the lowest net cost,
+//where every unit past `amount` is worth `leftover_value` back.
+//a cover that can't drop any listing holds fewer than amount + largest listing units,
+//so only quantities up to there are searched, which keeps it at O(listings * (amount + largest listing)).
+fn compute(
+    listings: Vec<ItemListing>,
+    item_id: usize,
+    location: &String,
+    amount: usize,
+    leftover_value: f32,
+) -> Option<PurchasePlan> {
+    let leftover_value = credited_leftover_value(&listings, leftover_value);
+    let listing_count = listings.len();
+    trace!(
+        "getting cheapest item_id:{item_id} location:{location} amount:{amount} leftover value: {leftover_value} listings count: {listing_count}"
+    );
+    let start_time = SystemTime::now();
+
+    let largest = listings.iter().map(|l| l.quantity).max().unwrap_or(0);
+    let bound = amount + largest.saturating_sub(1);
+    let knapsack = Knapsack::new(&listings, bound);
+    let net = |q: usize, cost: usize| cost as f32 - (q - amount) as f32 * leftover_value;
+    let cheapest = (amount..=bound)
+        .filter_map(|q| knapsack.best[q].map(|(cost, count)| (q, cost, count)))
+        .min_by(|a, b| {
+            net(a.0, a.1)
+                .total_cmp(&net(b.0, b.1))
+                .then(a.2.cmp(&b.2))
+                .then(a.0.cmp(&b.0))
+        })
+        .map(|(q, _, _)| PurchasePlan::new(knapsack.pick(&listings, q), amount, leftover_value));
+    trace!(
+        "finished item_id:{item_id} location:{location} amount:{amount} in {:?}",
+        start_time.elapsed().unwrap()
+    );
+    cheapest
+}
+
+//like compute, but HQ and NQ listings are knapsacked separately so the plan can be steered by how many HQ units it holds.
+//any split of the two that covers `amount` is a candidate, the mode picks between them.
+fn compute_mixed(
+    listings: &[ItemListing],
+    item_id: usize,
+    location: &String,
+    amount: usize,
+    leftover_value: f32,
+    mode: HqMode,
+) -> Option<PurchasePlan> {
+    trace!(
+        "getting mixed cheapest item_id:{item_id} location:{location} amount:{amount} mode: {mode:?}"
+    );
+    let start_time = SystemTime::now();
+    let leftover_value = credited_leftover_value(listings, leftover_value);
+    let (hq, nq): (Vec<ItemListing>, Vec<ItemListing>) =
+        listings.iter().cloned().partition(|l| l.hq);
+    let largest = listings.iter().map(|l| l.quantity).max().unwrap_or(0);
+    let bound = amount + largest.saturating_sub(1);
+    let hq_knapsack = Knapsack::new(&hq, bound);
+    let nq_knapsack = Knapsack::new(&nq, bound);
+
+    let (hq_best, nq_best) = (&hq_knapsack.best, &nq_knapsack.best);
+    //nq_suffix[m]: the nq units n >= m with the lowest net cost, then fewest listings, then fewest units.
+    //the best split for h hq units takes n from nq_suffix[amount - h], so every h is looked at once
+    let nq_key = |n: usize| {
+        nq_best[n].map(|(cost, count)| (cost as f32 - n as f32 * leftover_value, count, n))
+    };
+    let mut nq_suffix: Vec<Option<(f32, usize, usize)>> = vec![None; bound + 2];
+    for n in (0..=bound).rev() {
+        nq_suffix[n] = match (nq_key(n), nq_suffix[n + 1]) {
+            (Some(k), Some(s)) if k.0.total_cmp(&s.0).then(k.1.cmp(&s.1)).is_gt() => Some(s),
+            (Some(k), _) => Some(k),
+            (None, s) => s,
+        };
+    }
+    //(hq units, nq units, net cost, listing count), the best split for each number of hq units
+    let splits: Vec<(usize, usize, f32, usize)> = (0..=bound)
+        .filter_map(|h| {
+            let (hq_cost, hq_count) = hq_best[h]?;
+            let (_, _, n) = nq_suffix[amount.saturating_sub(h)]?;
+            let (nq_cost, nq_count) = nq_best[n]?;
+            let net = (hq_cost + nq_cost) as f32 - (h + n - amount) as f32 * leftover_value;
+            Some((h, n, net, hq_count + nq_count))
+        })
+        .collect();
+    let cheapest_with = |min_hq: usize| {
+        splits
+            .iter()
+            .copied()
+            .filter(|s| s.0 >= min_hq)
+            .min_by(|a, b| {
+                a.2.total_cmp(&b.2)
+                    .then(a.3.cmp(&b.3))
+                    .then((a.0 + a.1).cmp(&(b.0 + b.1)))
+            })
+    };
+    let chosen = match mode {
+        HqMode::Any => cheapest_with(0),
+        HqMode::Prefer => cheapest_with(amount),
+        HqMode::AtLeast(k) => cheapest_with(k),
+        HqMode::Budget(budget) => {
+            let limit = cheapest_with(0)?.2 + budget as f32;
+            //most HQ units that count toward the amount, then cheapest
+            splits
+                .iter()
+                .copied()
+                .filter(|s| s.2 <= limit)
+                .max_by(|a, b| {
+                    a.0.min(amount)
+                        .cmp(&b.0.min(amount))
+                        .then(b.2.total_cmp(&a.2))
+                        .then(b.3.cmp(&a.3))
+                })
+        }
+    };
+    let plan = chosen.map(|(h, n, _, _)| {
+        let mut picked = hq_knapsack.pick(&hq, h);
+        picked.extend(nq_knapsack.pick(&nq, n));
+        PurchasePlan::new(picked, amount, leftover_value)
+    });
+    trace!(
+        "finished mixed item_id:{item_id} location:{location} amount:{amount} in {:?}",
+        start_time.elapsed().unwrap()
+    );
+    plan
+}
 
 pub(super) async fn get_cheapest_combination(
     item_id: usize,
     location: String,
-    cache: &InMemoryCache,
+    cache: &Arc<dyn MarketCache>,
     amount: usize,
-    hq: bool,
-) -> Vec<ItemListing> {
-    let listings = get_item_listings(&location, item_id, &cache).await;
-    fn compute(
-        listings: Vec<ItemListing>,
-        item_id: usize,
-        location: &String,
-        amount: usize,
-        hq: bool,
-    ) -> Option<Vec<ItemListing>> {
-        let mut cheapest: Option<Vec<ItemListing>> = None;
-
-        let listing_count = listings.clone().iter().count();
-        let mut loop_count: i64 = 0;
+    options: PlanOptions,
+) -> Result<PurchasePlan, MarketError> {
+    let cached = get_item_listings(&location, item_id, cache).await?;
+    let fetched_at = Some(fetched_at(cached.age));
+    let mut listings = cached.data;
+    let version = listings_version(&listings);
+    //check cache
+    if let Some(mut v) = cache.get_cheapest(item_id, location.clone(), amount, &options, version) {
+        //the same listings may have been fetched again since
+        v.fetched_at = fetched_at;
+        return Ok(v);
+    }
+    //cache miss, or the listings were refreshed since
+    if let Some(worlds) = &options.travel.worlds {
+        listings.retain(|l| worlds.contains(&l.world_id));
+    } else if options.travel.is_active() {
+        let worlds = route::choose_worlds(&[(&listings, amount)], &options.travel);
         trace!(
-        "getting cheapest item_id:{item_id} location:{location} amount:{amount} hq: {hq} listings count: {listing_count}"
-    );
-        let start_time = SystemTime::now();
-        for i in 1..listing_count {
-            //quit when the next search space is massive
-            if listing_count.pow(i as u32) > 10_usize.pow(8) {
-                //trace!("stopping search dude to huge search space");
-                break;
-            }
-            //if we have a potential solution already
-            if let Some(ref c) = cheapest {
-                //ignore solutions > size of current best + 1
-                if i > c.len() + 1 {
-                    break;
+            "shopping item_id:{item_id} location:{location} amount:{amount} on worlds {worlds:?}"
+        );
+        listings.retain(|l| worlds.contains(&l.world_id));
+    }
+    //available on every world and never runs out, so it's added after picking worlds
+    if let Some(price) = options.vendor_price {
+        listings.extend(vendor_listings(item_id, price, amount));
+    }
+    let leftover_value = match options.leftovers {
+        Some(estimate) => get_sale_price(&location, item_id, estimate, cache).await?,
+        None => 0.0,
+    };
+    let mut plan = match options.hq {
+        HqMode::Any => compute(listings, item_id, &location, amount, leftover_value),
+        HqMode::Prefer => compute_mixed(
+            &listings,
+            item_id,
+            &location,
+            amount,
+            leftover_value,
+            HqMode::Prefer,
+        )
+        .or_else(|| {
+            //the plan's hq_quantity shows how much of it ended up HQ
+            trace!("No HQ combinations found, trying any combination: item_id:{item_id} location:{location} amount:{amount}");
+            compute(listings.clone(), item_id, &location, amount, leftover_value)
+        }),
+        mode => compute_mixed(&listings, item_id, &location, amount, leftover_value, mode),
+    }
+    .unwrap_or_else(|| PurchasePlan::new(Vec::new(), amount, leftover_value));
+    plan.listings = merge_vendor_listings(plan.listings);
+    let mut plan = plan.routed(&options.travel);
+    plan.listings_version = version;
+    plan.fetched_at = fetched_at;
+    cache.set_cheapest(item_id, location.clone(), amount, &options, plan.clone());
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market::testing::{listing, Rng};
+
+    //few distinct prices and quantities so ties come up, some stacks larger than any amount asked for
+    fn random_listings(rng: &mut Rng, max: usize) -> Vec<ItemListing> {
+        (0..rng.below(max + 1))
+            .map(|_| {
+                let quantity = match rng.below(5) {
+                    0 => 20 + rng.below(80),
+                    _ => 1 + rng.below(6),
+                };
+                listing(1, quantity, 1 + rng.below(4), rng.below(2) == 0)
+            })
+            .collect()
+    }
+
+    //(units, cost) of every subset
+    fn subsets(listings: &[ItemListing]) -> Vec<(usize, usize)> {
+        (0..1usize << listings.len())
+            .map(|mask| {
+                listings
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| mask & (1 << i) != 0)
+                    .fold((0, 0), |(q, c), (_, l)| (q + l.quantity, c + l.total_price))
+            })
+            .collect()
+    }
+
+    fn units_and_cost(listings: &[ItemListing]) -> (usize, usize) {
+        (
+            listings.iter().map(|l| l.quantity).sum(),
+            listings.iter().map(|l| l.total_price).sum(),
+        )
+    }
+
+    #[test]
+    fn knapsack_matches_brute_force() {
+        let mut rng = Rng(0x9e3779b97f4a7c15);
+        for _ in 0..300 {
+            let listings = random_listings(&mut rng, 8);
+            let max_quantity = rng.below(40);
+            let knapsack = Knapsack::new(&listings, max_quantity);
+            let subsets = subsets(&listings);
+            for q in 0..=max_quantity {
+                let brute = subsets.iter().filter(|s| s.0 == q).map(|s| s.1).min();
+                assert_eq!(knapsack.best[q].map(|b| b.0), brute, "quantity {q}");
+                if let Some(cost) = brute {
+                    assert_eq!(units_and_cost(&knapsack.pick(&listings, q)), (q, cost));
                 }
             }
-            for listing_combination in listings.iter().combinations(i) {
-                loop_count += 1;
-                let mut amt = 0;
-                let mut cost = 0;
-                for listing in listing_combination.clone() {
-                    amt += listing.quantity;
-                    cost += listing.total_price;
+        }
+    }
+
+    #[test]
+    fn compute_matches_brute_force() {
+        let location = String::from("test");
+        let mut rng = Rng(0xdeadbeefcafef00d);
+        for _ in 0..300 {
+            let listings = random_listings(&mut rng, 8);
+            let amount = 1 + rng.below(30);
+            let brute = subsets(&listings)
+                .into_iter()
+                .filter(|s| s.0 >= amount)
+                .map(|s| s.1)
+                .min();
+            let plan = compute(listings.clone(), 1, &location, amount, 0.0);
+            match brute {
+                Some(cost) => {
+                    let plan = plan.expect("a cover exists");
+                    assert!(plan.fulfilled);
+                    assert_eq!(plan.total_cost, cost);
+                    assert!(plan.quantity >= amount);
                 }
-                if amt >= amount {
-                    match cheapest {
-                        None => {
-                            cheapest = {
-                                let mut new_cheapest = Vec::new();
-                                for listing in listing_combination.clone() {
-                                    new_cheapest.push(listing.clone());
-                                }
-                                Some(new_cheapest)
-                            }
-                        }
-
-                        Some(ref old_cheapest) => {
-                            let current_cheapest_price: usize =
-                                old_cheapest.clone().iter().map(|l| l.total_price).sum();
-                            if cost < current_cheapest_price {
-                                let mut new_cheapest = Vec::new();
-                                for listing in listing_combination.clone() {
-                                    new_cheapest.push(listing.clone());
-                                }
-                                cheapest = Some(new_cheapest.to_owned());
-                            }
-                        }
-                    }
-                };
+                //more than everything listed
+                None => assert!(plan.is_none_or(|p| !p.fulfilled)),
             }
         }
-        trace!(
-        "finished item_id:{item_id} location:{location} amount:{amount} hq: {hq} in {:?} after {loop_count} iterations",
-        start_time.elapsed().unwrap()
-        );
-        cheapest
     }
-    
-    //check cache
-    if let Some(v) = cache.get_cheapest(item_id, location.clone(), amount, hq) {
-        return v;
-    }
-    //cache miss
-    if hq {
-        let hq_listings = listings.iter().filter(|l| l.hq == true).cloned().collect();
-        match compute(hq_listings, item_id, &location, amount, hq) {
-            Some(c) => {
-                //update cache
-                cache.set_cheapest(item_id, location.clone(), amount, hq, c.clone());
-                c
-            }
-            None => {
-                trace!("No HQ combinations found, trying any combination: item_id:{item_id} location:{location} amount:{amount}");
-                let c = compute(listings, item_id, &location, amount, false).unwrap_or_default();
-
-                //update both hq and nq cache as they evaluated to be the same
-                cache.set_cheapest(item_id, location.clone(), amount, hq, c.clone());
-                cache.set_cheapest(item_id, location.clone(), amount, false, c.clone());
-                c
+
+    #[test]
+    fn compute_mixed_matches_brute_force() {
+        let location = String::from("test");
+        let mut rng = Rng(0x0123456789abcdef);
+        for _ in 0..300 {
+            let listings = random_listings(&mut rng, 8);
+            let amount = 1 + rng.below(20);
+            let min_hq = rng.below(amount + 1);
+            //(units, hq units, cost) of every subset
+            let brute = (0..1usize << listings.len())
+                .map(|mask| {
+                    listings
+                        .iter()
+                        .enumerate()
+                        .filter(|(i, _)| mask & (1 << i) != 0)
+                        .fold((0, 0, 0), |(q, h, c), (_, l)| {
+                            (
+                                q + l.quantity,
+                                h + if l.hq { l.quantity } else { 0 },
+                                c + l.total_price,
+                            )
+                        })
+                })
+                .filter(|s| s.0 >= amount && s.1 >= min_hq)
+                .map(|s| s.2)
+                .min();
+            let plan = compute_mixed(
+                &listings,
+                1,
+                &location,
+                amount,
+                0.0,
+                HqMode::AtLeast(min_hq),
+            );
+            match brute {
+                Some(cost) => {
+                    let plan = plan.expect("a cover exists");
+                    assert_eq!(plan.total_cost, cost);
+                    assert!(plan.quantity >= amount && plan.hq_quantity >= min_hq);
+                }
+                None => assert!(plan.is_none()),
             }
         }
-    } else {
-        let c = compute(listings, item_id, &location, amount, hq).unwrap_or_default();
-        cache.set_cheapest(item_id, location.clone(), amount, hq, c.clone());
-        c
+    }
+
+    #[test]
+    fn compute_handles_empty_and_oversized_listings() {
+        let location = String::from("test");
+        assert!(compute(Vec::new(), 1, &location, 5, 0.0).is_none());
+        //one stack far larger than needed still beats small ones priced higher in total
+        let listings = vec![listing(1, 99, 1, false), listing(1, 3, 40, false)];
+        let plan = compute(listings, 1, &location, 2, 0.0).unwrap();
+        assert_eq!((plan.quantity, plan.total_cost), (99, 99));
+    }
+
+    #[test]
+    fn leftovers_are_not_worth_more_than_they_cost() {
+        let location = String::from("test");
+        let listings = vec![listing(1, 99, 50, false), listing(1, 1, 100, false)];
+        let plan = compute(listings, 1, &location, 1, 120.0).unwrap();
+        //the 98 extra units are credited at 50, not 120
+        assert_eq!(plan.surplus_value, 98.0 * 50.0);
+        assert_eq!(plan.net_cost, 50.0);
     }
 }
@@ -0,0 +1,277 @@
+use std::{cmp::Reverse, collections::BTreeMap};
+
+use crate::market::{ItemListing, TravelOptions};
+
+//worlds the search looks at, a data center's worth. every combination of them is tried,
+//so a region location only has its cheapest worlds considered
+const MAX_ROUTE_WORLDS: usize = 8;
+
+//cheapest[q]: cheapest price of a subset of the listings added so far holding q units,
+//with anything past the last index counted as the last index
+fn add_listings(cheapest: &mut [Option<usize>], listings: &[&ItemListing]) {
+    let amount = cheapest.len() - 1;
+    for listing in listings.iter().filter(|l| l.quantity > 0) {
+        //walk down so cheapest[q] doesn't already include this listing
+        for q in (0..=amount).rev() {
+            let Some(cost) = cheapest[q] else {
+                continue;
+            };
+            let target = (q + listing.quantity).min(amount);
+            let candidate = cost + listing.total_price;
+            if cheapest[target].is_none_or(|c| candidate < c) {
+                cheapest[target] = Some(candidate);
+            }
+        }
+    }
+}
+
+fn cover_costs(listings: &[&ItemListing], amount: usize) -> Vec<Option<usize>> {
+    let mut cheapest = vec![None; amount + 1];
+    cheapest[0] = Some(0);
+    add_listings(&mut cheapest, listings);
+    cheapest
+}
+
+//price per unit of as much of `amount` as these listings cover, the cheapest way
+fn unit_price(listings: &[&ItemListing], amount: usize) -> f32 {
+    cover_costs(listings, amount)
+        .iter()
+        .enumerate()
+        .skip(1)
+        .rev()
+        .find_map(|(q, c)| c.map(|c| c as f32 / q as f32))
+        .unwrap_or(f32::INFINITY)
+}
+
+//picks the worlds to shop on for every (listings, amount) in `needs` at once: the cheapest cover of
+//each amount once every world visited costs `world_penalty` (the home world is free), visiting at most
+//`max_worlds` in total. covering more of the needs comes before price, a need no choice of worlds covers
+//is bought as far as the chosen worlds go.
+//only quantity counts here, quality and leftovers are left to the optimizer run on the chosen worlds' listings
+pub(crate) fn choose_worlds(
+    needs: &[(&[ItemListing], usize)],
+    travel: &TravelOptions,
+) -> Vec<usize> {
+    //need -> world id -> listings
+    let by_world: Vec<BTreeMap<usize, Vec<&ItemListing>>> = needs
+        .iter()
+        .map(|(listings, _)| {
+            let mut by_world: BTreeMap<usize, Vec<&ItemListing>> = BTreeMap::new();
+            for listing in listings.iter() {
+                by_world.entry(listing.world_id).or_default().push(listing);
+            }
+            by_world
+        })
+        .collect();
+    let mut worlds: Vec<usize> = by_world.iter().flat_map(|w| w.keys().copied()).collect();
+    worlds.sort_unstable();
+    worlds.dedup();
+    if worlds.len() > MAX_ROUTE_WORLDS {
+        //the home world, then the worlds that are cheapest for some need, taking turns between the needs
+        let mut rank: BTreeMap<usize, usize> = BTreeMap::new();
+        for (need, (_, amount)) in by_world.iter().zip(needs) {
+            let mut prices: Vec<(f32, usize)> = need
+                .iter()
+                .map(|(world_id, l)| (unit_price(l, *amount), *world_id))
+                .collect();
+            prices.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+            for (r, (_, world_id)) in prices.into_iter().enumerate() {
+                let best = rank.entry(world_id).or_insert(r);
+                *best = r.min(*best);
+            }
+        }
+        worlds.sort_by_key(|w| (Some(*w) != travel.home_world, rank[w], *w));
+        worlds.truncate(MAX_ROUTE_WORLDS);
+        worlds.sort_unstable();
+    }
+    let max_worlds = travel.max_worlds.unwrap_or(worlds.len());
+
+    //covered[m]: (needs covered, their price) on the worlds in mask m
+    let masks = 1usize << worlds.len();
+    let mut covered = vec![(0usize, 0usize); masks];
+    for (need, (_, amount)) in by_world.iter().zip(needs) {
+        //each mask's table is its lower masks' one plus the listings of its highest world
+        let mut tables: Vec<Vec<Option<usize>>> = Vec::with_capacity(masks);
+        tables.push(cover_costs(&[], *amount));
+        for mask in 1..masks {
+            let top = usize::BITS - 1 - mask.leading_zeros();
+            let mut table = tables[mask ^ (1 << top)].clone();
+            if let Some(l) = need.get(&worlds[top as usize]) {
+                add_listings(&mut table, l);
+            }
+            tables.push(table);
+        }
+        for (mask, table) in tables.iter().enumerate() {
+            if let Some(cost) = table[*amount] {
+                covered[mask].0 += 1;
+                covered[mask].1 += cost;
+            }
+        }
+    }
+
+    let penalty = |w: usize| match travel.home_world {
+        Some(home) if home == w => 0,
+        _ => travel.world_penalty,
+    };
+    let best = (0..masks)
+        .filter(|mask| mask.count_ones() as usize <= max_worlds)
+        .min_by_key(|mask| {
+            let (count, cost) = covered[*mask];
+            let travel_cost: usize = (0..worlds.len())
+                .filter(|i| mask & (1 << i) != 0)
+                .map(|i| penalty(worlds[i]))
+                .sum();
+            (Reverse(count), cost + travel_cost, mask.count_ones())
+        })
+        .unwrap_or(0);
+    (0..worlds.len())
+        .filter(|i| best & (1 << i) != 0)
+        .map(|i| worlds[i])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market::testing::{listing, Rng};
+
+    fn travel(
+        world_penalty: usize,
+        max_worlds: Option<usize>,
+        home_world: Option<usize>,
+    ) -> TravelOptions {
+        TravelOptions {
+            world_penalty,
+            max_worlds,
+            home_world,
+            worlds: None,
+        }
+    }
+
+    //cheapest cover of `amount` on `worlds` plus their penalties, None if they can't cover it
+    fn route_cost(
+        listings: &[ItemListing],
+        worlds: &[usize],
+        amount: usize,
+        travel: &TravelOptions,
+    ) -> Option<usize> {
+        let on_route: Vec<&ItemListing> = listings
+            .iter()
+            .filter(|l| worlds.contains(&l.world_id))
+            .collect();
+        cover_costs(&on_route, amount)[amount].map(|c| c + travel_cost(worlds, travel))
+    }
+
+    fn travel_cost(worlds: &[usize], travel: &TravelOptions) -> usize {
+        worlds
+            .iter()
+            .filter(|w| Some(**w) != travel.home_world)
+            .count()
+            * travel.world_penalty
+    }
+
+    //the cheapest worlds for one item
+    fn choose(listings: &[ItemListing], amount: usize, travel: &TravelOptions) -> Vec<usize> {
+        choose_worlds(&[(listings, amount)], travel)
+    }
+
+    #[test]
+    fn penalty_trades_price_for_fewer_worlds() {
+        let listings = vec![
+            listing(1, 10, 10, false),
+            listing(2, 5, 5, false),
+            listing(3, 5, 5, false),
+        ];
+        assert_eq!(choose(&listings, 10, &travel(0, None, None)), vec![2, 3]);
+        assert_eq!(choose(&listings, 10, &travel(100, None, None)), vec![1]);
+        //the home world costs nothing to visit
+        assert_eq!(
+            choose(&listings, 10, &travel(60, None, Some(2))),
+            vec![2, 3]
+        );
+    }
+
+    #[test]
+    fn max_worlds_limits_the_route() {
+        let listings = vec![
+            listing(1, 10, 10, false),
+            listing(2, 5, 5, false),
+            listing(3, 5, 5, false),
+        ];
+        assert_eq!(choose(&listings, 10, &travel(0, Some(1), None)), vec![1]);
+        assert!(choose(&listings, 11, &travel(0, Some(1), None)).is_empty());
+        assert!(choose(&listings, 21, &travel(0, None, None)).is_empty());
+    }
+
+    #[test]
+    fn worlds_are_shared_between_items() {
+        //each item alone is cheapest on a world of its own
+        let a = vec![listing(1, 10, 10, false), listing(2, 10, 11, false)];
+        let b = vec![listing(3, 10, 10, false), listing(2, 10, 11, false)];
+        let needs = [(a.as_slice(), 10), (b.as_slice(), 10)];
+        assert_eq!(choose_worlds(&needs, &travel(0, None, None)), vec![1, 3]);
+        assert_eq!(choose_worlds(&needs, &travel(0, Some(1), None)), vec![2]);
+        //a world visited for one item is free for the other
+        assert_eq!(choose_worlds(&needs, &travel(30, None, None)), vec![2]);
+    }
+
+    #[test]
+    fn choice_matches_brute_force() {
+        let mut rng = Rng(0x5eed5eed5eed5eed);
+        for _ in 0..200 {
+            let world_count = 1 + rng.below(5);
+            let needs: Vec<(Vec<ItemListing>, usize)> = (0..1 + rng.below(3))
+                .map(|_| {
+                    let listings = (0..1 + rng.below(10))
+                        .map(|_| {
+                            listing(
+                                1 + rng.below(world_count),
+                                1 + rng.below(8),
+                                1 + rng.below(5),
+                                false,
+                            )
+                        })
+                        .collect();
+                    (listings, 1 + rng.below(20))
+                })
+                .collect();
+            let travel = travel(
+                rng.below(30),
+                rng.below(2)
+                    .checked_sub(1)
+                    .map(|_| 1 + rng.below(world_count)),
+                rng.below(2)
+                    .checked_sub(1)
+                    .map(|_| 1 + rng.below(world_count)),
+            );
+            let max_worlds = travel.max_worlds.unwrap_or(world_count);
+            //(needs covered, price plus travel) of a set of worlds
+            let score = |worlds: &[usize]| {
+                let costs: Vec<usize> = needs
+                    .iter()
+                    .filter_map(|(l, amount)| route_cost(l, worlds, *amount, &travel))
+                    .map(|c| c - travel_cost(worlds, &travel))
+                    .collect();
+                (
+                    Reverse(costs.len()),
+                    costs.iter().sum::<usize>() + travel_cost(worlds, &travel),
+                )
+            };
+            let brute = (0..1usize << world_count)
+                .filter(|mask| mask.count_ones() as usize <= max_worlds)
+                .map(|mask| {
+                    let worlds: Vec<usize> = (1..=world_count)
+                        .filter(|w| mask & (1 << (w - 1)) != 0)
+                        .collect();
+                    score(&worlds)
+                })
+                .min()
+                .unwrap();
+            let needs_ref: Vec<(&[ItemListing], usize)> =
+                needs.iter().map(|(l, a)| (l.as_slice(), *a)).collect();
+            let chosen = choose_worlds(&needs_ref, &travel);
+            assert!(chosen.len() <= max_worlds);
+            assert_eq!(score(&chosen), brute);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+
+use crate::market::ItemListing;
+
+//where the selling retainer is registered, each city sets its own market board tax
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum City {
+    LimsaLominsa,
+    Gridania,
+    UlDah,
+    Ishgard,
+    Kugane,
+    Crystarium,
+    OldSharlayan,
+    Tuliyollal,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TaxModel {
+    //fraction of every sale kept by the market board
+    pub(crate) sale_rate: f32,
+    //fraction paid on top of every market board purchase, vendors don't charge it
+    pub(crate) purchase_rate: f32,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Revenue {
+    pub(crate) gross: f32,
+    pub(crate) tax: f32,
+    pub(crate) net: f32,
+}
+
+//percentages, so `5` is 5%
+fn rate_from_env(var: &str) -> f32 {
+    env::var(var)
+        .unwrap_or(String::from("5"))
+        .parse::<f32>()
+        .unwrap_or(5.0)
+        / 100.0
+}
+
+//sale tax per city from XIVP_CITY_TAX: `ul_dah=3;kugane=0`
+fn city_rate_from_env(city: City) -> Option<f32> {
+    env::var("XIVP_CITY_TAX")
+        .unwrap_or_default()
+        .split(';')
+        .filter_map(|c| {
+            let (name, rate) = c.split_once('=')?;
+            let name: City = serde_json::from_value(name.trim().into()).ok()?;
+            let rate: f32 = rate.trim().parse().ok()?;
+            Some((name, rate / 100.0))
+        })
+        .find(|(name, _)| *name == city)
+        .map(|(_, rate)| rate)
+}
+
+impl TaxModel {
+    //XIVP_SALE_TAX unless the city has its own rate, XIVP_PURCHASE_TAX for purchases
+    pub(crate) fn new(city: Option<City>) -> Self {
+        Self {
+            sale_rate: city
+                .and_then(city_rate_from_env)
+                .unwrap_or_else(|| rate_from_env("XIVP_SALE_TAX")),
+            purchase_rate: rate_from_env("XIVP_PURCHASE_TAX"),
+        }
+    }
+
+    pub(crate) fn revenue(&self, gross: f32) -> Revenue {
+        let tax = gross * self.sale_rate;
+        Revenue {
+            gross,
+            tax,
+            net: gross - tax,
+        }
+    }
+
+    pub(crate) fn purchase_tax(&self, listings: &[ItemListing]) -> f32 {
+        let bought: usize = listings
+            .iter()
+            .filter(|l| !l.vendor)
+            .map(|l| l.total_price)
+            .sum();
+        bought as f32 * self.purchase_rate
+    }
+}
@@ -0,0 +1,32 @@
+//fixtures for the market tests
+use crate::market::ItemListing;
+
+pub(super) fn listing(
+    world_id: usize,
+    quantity: usize,
+    price_per_unit: usize,
+    hq: bool,
+) -> ItemListing {
+    ItemListing {
+        item_id: 1,
+        world_id,
+        price_per_unit: price_per_unit as f32,
+        quantity,
+        total_price: price_per_unit * quantity,
+        hq,
+        retainer_name: String::new(),
+        vendor: false,
+    }
+}
+
+//xorshift, the tests only need something repeatable
+pub(super) struct Rng(pub(super) u64);
+
+impl Rng {
+    pub(super) fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
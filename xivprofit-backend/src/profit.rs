@@ -0,0 +1,178 @@
+use futures::future::join_all;
+use serde::Serialize;
+use std::sync::Arc;
+
+pub(crate) mod scan;
+pub(crate) mod watch;
+
+use crate::{
+    cache::MarketCache,
+    crafting::{ItemData, Job, Recipe},
+    market::{
+        self,
+        estimator::SalePriceEstimate,
+        route,
+        tax::{Revenue, TaxModel},
+        ItemListing, MarketError, PlanOptions, PurchasePlan, RunningJobs, WorldStop,
+    },
+};
+
+#[derive(Clone, Serialize)]
+pub(crate) struct IngredientCost {
+    pub(crate) item_id: usize,
+    //total amount needed for all crafts
+    pub(crate) amount: usize,
+    pub(crate) plan: PurchasePlan,
+}
+
+#[derive(Clone, Serialize)]
+pub(crate) struct RecipeProfit {
+    pub(crate) recipe_id: usize,
+    pub(crate) result_item_id: usize,
+    pub(crate) job: Option<Job>,
+    pub(crate) level: usize,
+    pub(crate) crafts: usize,
+    //items produced by all crafts
+    pub(crate) units: usize,
+    pub(crate) ingredients: Vec<IngredientCost>,
+    //every ingredient's listings as one trip, each world visited once
+    pub(crate) route: Vec<WorldStop>,
+    pub(crate) total_cost: usize,
+    //tax on the ingredients bought off the market board, already in cost_per_unit
+    pub(crate) purchase_tax: f32,
+    //what leftover ingredients are assumed to be worth, already taken off cost_per_unit
+    pub(crate) surplus_value: f32,
+    //world_penalty for every world on the route besides the home world
+    pub(crate) travel_cost: usize,
+    pub(crate) cost_per_unit: f32,
+    pub(crate) sale_price: f32,
+    //every unit sold at sale_price, margins are taken from the net
+    pub(crate) revenue: Revenue,
+    pub(crate) margin_per_unit: f32,
+    pub(crate) margin_per_craft: f32,
+    //units of the result sold per day at this location
+    pub(crate) sale_velocity: f32,
+    //margin on the units the market actually absorbs in a day
+    pub(crate) expected_gil_per_day: f32,
+    pub(crate) fulfilled: bool,
+    //unix seconds the oldest listings of the ingredients and the result were fetched from universalis
+    pub(crate) fetched_at: Option<u64>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn get_recipe_profit(
+    recipe: &Recipe,
+    item_data: &ItemData,
+    location: &String,
+    crafts: usize,
+    options: PlanOptions,
+    estimate: SalePriceEstimate,
+    tax: TaxModel,
+    cache: &Arc<dyn MarketCache>,
+    jobs: &RunningJobs,
+) -> Result<RecipeProfit, MarketError> {
+    //one upstream request for the whole recipe, everything below is then served from the cache
+    let mut item_ids: Vec<usize> = recipe.ingredients.iter().map(|(id, _)| *id).collect();
+    item_ids.push(recipe.result_item_id);
+    let listings = market::get_items_listings(location, &item_ids, cache).await?;
+    let fetched_at = listings.values().map(|l| market::fetched_at(l.age)).min();
+
+    //one trip for every ingredient, so max_worlds and world_penalty apply to the recipe as a whole
+    let mut options = options;
+    if options.travel.is_active() {
+        let needs: Vec<(&[ItemListing], usize)> = recipe
+            .ingredients
+            .iter()
+            .filter_map(|(item_id, amount)| {
+                let l = listings.get(item_id)?;
+                Some((l.data.as_slice(), amount * crafts))
+            })
+            .collect();
+        options.travel.worlds = Some(route::choose_worlds(&needs, &options.travel));
+    }
+
+    let ingredients = join_all(recipe.ingredients.iter().map(|(item_id, amount)| {
+        get_ingredient_cost(
+            *item_id,
+            amount * crafts,
+            location,
+            options.clone().for_item(item_data.get_item(*item_id)),
+            cache,
+            jobs,
+        )
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    let sale_price =
+        market::get_sale_price(location, recipe.result_item_id, estimate, cache).await?;
+    let sale_velocity = market::get_sale_history(location, recipe.result_item_id, cache)
+        .await?
+        .velocity(estimate.quality);
+
+    let units = recipe.result_item_quantity * crafts;
+    let total_cost: usize = ingredients.iter().map(|i| i.plan.total_cost).sum();
+    let surplus_value: f32 = ingredients.iter().map(|i| i.plan.surplus_value).sum();
+    let listings: Vec<_> = ingredients
+        .iter()
+        .flat_map(|i| i.plan.listings.iter().cloned())
+        .collect();
+    let route = market::plan_route(&listings, &options.travel);
+    let travel_cost = options.travel.penalty_for(&route);
+    let purchase_tax = tax.purchase_tax(&listings);
+    let cost_per_unit =
+        (total_cost as f32 + purchase_tax - surplus_value + travel_cost as f32) / units as f32;
+    let margin_per_unit = tax.revenue(sale_price).net - cost_per_unit;
+    Ok(RecipeProfit {
+        recipe_id: recipe.id,
+        result_item_id: recipe.result_item_id,
+        job: recipe.job,
+        level: recipe.level,
+        crafts,
+        units,
+        fulfilled: ingredients.iter().all(|i| i.plan.fulfilled),
+        ingredients,
+        route,
+        total_cost,
+        purchase_tax,
+        surplus_value,
+        travel_cost,
+        cost_per_unit,
+        sale_price,
+        revenue: tax.revenue(sale_price * units as f32),
+        margin_per_unit,
+        margin_per_craft: margin_per_unit * recipe.result_item_quantity as f32,
+        sale_velocity,
+        expected_gil_per_day: margin_per_unit * sale_velocity,
+        fetched_at,
+    })
+}
+
+async fn get_ingredient_cost(
+    item_id: usize,
+    amount: usize,
+    location: &str,
+    options: PlanOptions,
+    cache: &Arc<dyn MarketCache>,
+    jobs: &RunningJobs,
+) -> Result<IngredientCost, MarketError> {
+    let plan = match market::get_cheapest_combination(
+        item_id,
+        location.to_string(),
+        cache,
+        amount,
+        options,
+        jobs,
+    )
+    .await
+    {
+        //an ingredient that isn't on the market board just can't be bought
+        Err(MarketError::ItemNotFound(_)) => PurchasePlan::new(Vec::new(), amount, 0.0),
+        r => r?,
+    };
+    Ok(IngredientCost {
+        item_id,
+        amount,
+        plan,
+    })
+}
@@ -0,0 +1,277 @@
+use futures::{stream, StreamExt};
+use log::{info, trace, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    cache::MarketCache,
+    crafting::{ItemData, Job, SharedItemData},
+    market::{
+        estimator::SalePriceEstimate,
+        tax::{Revenue, TaxModel},
+        HqMode, PlanOptions, RunningJobs, TravelOptions,
+    },
+    profit::get_recipe_profit,
+};
+
+//latest scan per location
+pub(crate) type ScanStore = Arc<RwLock<HashMap<String, ScanState>>>;
+
+#[derive(Clone, Serialize)]
+pub(crate) struct ScanEntry {
+    pub(crate) recipe_id: usize,
+    pub(crate) result_item_id: usize,
+    pub(crate) name: String,
+    pub(crate) job: Option<Job>,
+    pub(crate) level: usize,
+    pub(crate) cost_per_unit: f32,
+    pub(crate) sale_price: f32,
+    //selling a single craft's worth
+    pub(crate) revenue: Revenue,
+    //profit per unit as a fraction of the sale price
+    pub(crate) margin: f32,
+    //profit per unit as a fraction of the ingredient cost
+    pub(crate) roi: f32,
+    //profit of a single craft
+    pub(crate) profit: f32,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct ScanState {
+    pub(crate) in_progress: bool,
+    //unix seconds the last completed scan finished at
+    pub(crate) finished_at: Option<u64>,
+    pub(crate) entries: Vec<ScanEntry>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ProfitSort {
+    Margin,
+    Roi,
+    #[default]
+    Profit,
+}
+
+impl ScanEntry {
+    fn score(&self, sort: ProfitSort) -> f32 {
+        match sort {
+            ProfitSort::Margin => self.margin,
+            ProfitSort::Roi => self.roi,
+            ProfitSort::Profit => self.profit,
+        }
+    }
+}
+
+impl ScanState {
+    //never finished, or finished longer than XIVP_SCAN_INTERVAL ago
+    pub(crate) fn needs_scan(&self) -> bool {
+        if self.in_progress {
+            return false;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        self.finished_at
+            .is_none_or(|f| now.saturating_sub(f) >= scan_interval())
+    }
+
+    pub(crate) fn ranked(&self, sort: ProfitSort) -> Vec<ScanEntry> {
+        let mut entries = self.entries.clone();
+        entries.sort_by(|a, b| b.score(sort).total_cmp(&a.score(sort)));
+        entries
+    }
+}
+
+//seconds between scans of a location
+fn scan_interval() -> u64 {
+    env::var("XIVP_SCAN_INTERVAL")
+        .unwrap_or(String::from("3600"))
+        .parse()
+        .unwrap_or(3600)
+}
+
+//locations the store keeps results for, each one costs a full scan
+fn max_locations() -> usize {
+    env::var("XIVP_SCAN_MAX_LOCATIONS")
+        .unwrap_or(String::from("16"))
+        .parse()
+        .unwrap_or(16)
+}
+
+//marks the location as scanning, returns false if a scan is already running there.
+//a new location past XIVP_SCAN_MAX_LOCATIONS replaces the one scanned longest ago, or waits if they're all running
+fn try_start(store: &ScanStore, location: &str) -> bool {
+    let mut scans = store.write().unwrap();
+    if !scans.contains_key(location) && scans.len() >= max_locations() {
+        let oldest = scans
+            .iter()
+            .filter(|(_, s)| !s.in_progress)
+            .min_by_key(|(_, s)| s.finished_at)
+            .map(|(l, _)| l.clone());
+        match oldest {
+            Some(l) => {
+                trace!("dropping scan results for {l} to make room for {location}");
+                scans.remove(&l);
+            }
+            None => return false,
+        }
+    }
+    let state = scans.entry(location.to_string()).or_default();
+    if state.in_progress {
+        false
+    } else {
+        state.in_progress = true;
+        true
+    }
+}
+
+//score every recipe at a location with a single craft's worth of ingredients
+pub(crate) async fn run_scan(
+    location: String,
+    item_data: Arc<ItemData>,
+    cache: Arc<dyn MarketCache>,
+    jobs: RunningJobs,
+    store: ScanStore,
+) {
+    if !try_start(&store, &location) {
+        trace!("scan already running for {location}");
+        return;
+    }
+    info!(
+        "starting profit scan for {location}: {} recipes",
+        item_data.recipes.len()
+    );
+    let concurrency: usize = env::var("XIVP_SCAN_CONCURRENCY")
+        .unwrap_or(String::from("4"))
+        .parse()
+        .unwrap_or(4);
+    let names: HashMap<usize, &String> = item_data.items.iter().map(|i| (i.id, &i.name)).collect();
+
+    //untradable results can't be sold, so they have no profit to rank
+    let recipes: Vec<_> = item_data
+        .recipes
+        .iter()
+        .filter(|r| {
+            item_data
+                .get_item(r.result_item_id)
+                .is_none_or(|i| !i.untradable)
+        })
+        .cloned()
+        .collect();
+    let profits: Vec<_> = stream::iter(recipes)
+        .map(|r| {
+            let (location, cache, jobs, item_data) = (
+                location.clone(),
+                cache.clone(),
+                jobs.clone(),
+                item_data.clone(),
+            );
+            async move {
+                let estimate = SalePriceEstimate::new(None, None, None);
+                let options = PlanOptions {
+                    hq: HqMode::Any,
+                    leftovers: None,
+                    travel: TravelOptions::default(),
+                    vendor_price: None,
+                };
+                get_recipe_profit(
+                    &r,
+                    &item_data,
+                    &location,
+                    1,
+                    options,
+                    estimate,
+                    TaxModel::new(None),
+                    &cache,
+                    &jobs,
+                )
+                .await
+                .inspect_err(|e| warn!("scan skipped recipe {} @ {location}: {e}", r.id))
+                .ok()
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect()
+        .await;
+
+    let entries = profits
+        .into_iter()
+        .flatten()
+        //nothing to compare against without a full set of ingredients and a sale price
+        .filter(|p| p.fulfilled && p.sale_price > 0.0 && p.total_cost > 0)
+        .map(|p| ScanEntry {
+            recipe_id: p.recipe_id,
+            result_item_id: p.result_item_id,
+            name: names
+                .get(&p.result_item_id)
+                .map(|n| n.to_string())
+                .unwrap_or_default(),
+            job: p.job,
+            level: p.level,
+            cost_per_unit: p.cost_per_unit,
+            sale_price: p.sale_price,
+            revenue: p.revenue,
+            margin: p.margin_per_unit / p.sale_price,
+            roi: p.margin_per_unit / p.cost_per_unit,
+            profit: p.margin_per_craft,
+        })
+        .collect::<Vec<_>>();
+    info!(
+        "finished profit scan for {location}: {} scored recipes",
+        entries.len()
+    );
+
+    store.write().unwrap().insert(
+        location,
+        ScanState {
+            in_progress: false,
+            finished_at: Some(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs(),
+            ),
+            entries,
+        },
+    );
+}
+
+//rescan every location in XIVP_SCAN_LOCATIONS every XIVP_SCAN_INTERVAL seconds
+pub(crate) async fn run_scheduler(
+    item_data: Arc<SharedItemData>,
+    cache: Arc<dyn MarketCache>,
+    jobs: RunningJobs,
+    store: ScanStore,
+) {
+    let locations: Vec<String> = env::var("XIVP_SCAN_LOCATIONS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty())
+        .collect();
+    if locations.is_empty() {
+        info!("XIVP_SCAN_LOCATIONS is empty, profit scans will only run on request");
+        return;
+    }
+    let interval = scan_interval();
+    loop {
+        for location in locations.iter() {
+            run_scan(
+                location.clone(),
+                item_data.load(),
+                cache.clone(),
+                jobs.clone(),
+                store.clone(),
+            )
+            .await;
+        }
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
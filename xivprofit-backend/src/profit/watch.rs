@@ -0,0 +1,223 @@
+use futures::{stream, Stream};
+use log::{trace, warn};
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    env,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver},
+    time::{interval_at, Instant, Interval, MissedTickBehavior},
+};
+
+use crate::{
+    cache::{ListingsChanged, MarketCache},
+    crafting::ItemData,
+    market::{
+        self, estimator::SalePriceEstimate, listings_version, tax::TaxModel, HqMode, PlanOptions,
+        RunningJobs, TravelOptions,
+    },
+    profit::get_recipe_profit,
+};
+
+//most recipes re-scored per price change, an ingredient like a crystal goes into thousands.
+//recipes making the item come first, then the ones using the most of it
+const MAX_WATCHED_RECIPES: usize = 50;
+
+#[derive(Serialize)]
+pub(crate) struct RecipeChange {
+    pub(crate) recipe_id: usize,
+    pub(crate) result_item_id: usize,
+    //profit of a single craft, scored the same way as a scan
+    pub(crate) profit: f32,
+    //since the last update for this recipe, none for the first one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) delta: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PriceUpdate {
+    pub(crate) item_id: usize,
+    pub(crate) location: String,
+    //lowest unit price listed, none when nothing is
+    pub(crate) cheapest: Option<f32>,
+    //recipes that use the item as an ingredient or make it, at most MAX_WATCHED_RECIPES of them
+    pub(crate) recipes: Vec<RecipeChange>,
+    //how many recipes use or make the item, more than were scored when it's past the limit
+    pub(crate) total_recipes: usize,
+}
+
+struct Watcher {
+    item_ids: Vec<usize>,
+    location: String,
+    item_data: Arc<ItemData>,
+    cache: Arc<dyn MarketCache>,
+    jobs: RunningJobs,
+    changes: Receiver<ListingsChanged>,
+    //asks for the watched listings every XIVP_CACHE_TIMEOUT
+    refresh: Interval,
+    //items to check before waiting for the next change
+    pending: VecDeque<usize>,
+    //listings_version last reported per item
+    versions: HashMap<usize, u64>,
+    //profit last reported per recipe
+    profits: HashMap<usize, f32>,
+}
+
+impl Watcher {
+    async fn next_change(&mut self) -> Option<usize> {
+        loop {
+            if let Some(item_id) = self.pending.pop_front() {
+                return Some(item_id);
+            }
+            tokio::select! {
+                change = self.changes.recv() => match change {
+                    Ok(c) if c.location == self.location && self.item_ids.contains(&c.item_id) => {
+                        return Some(c.item_id)
+                    }
+                    Ok(_) => {}
+                    //missed some, check everything. unchanged items are skipped by their version
+                    Err(RecvError::Lagged(n)) => {
+                        trace!("price watch for {} missed {n} changes", self.location);
+                        self.pending.extend(self.item_ids.iter());
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                //with nobody else asking for them the listings would only go stale and expire.
+                //asking refreshes them, and the refresh comes back as a change
+                _ = self.refresh.tick() => {
+                    if let Err(e) =
+                        market::get_items_listings(&self.location, &self.item_ids, &self.cache).await
+                    {
+                        warn!("price watch couldn't refresh {}: {e}", self.location);
+                    }
+                }
+            }
+        }
+    }
+
+    //None when the listings are the same as last reported
+    async fn check(&mut self, item_id: usize) -> Option<PriceUpdate> {
+        let listings = market::get_item_listings(&self.location, item_id, &self.cache)
+            .await
+            .inspect_err(|e| {
+                warn!(
+                    "price watch skipped item {item_id} @ {}: {e}",
+                    self.location
+                )
+            })
+            .ok()?
+            .data;
+        let version = listings_version(&listings);
+        if self.versions.insert(item_id, version) == Some(version) {
+            return None;
+        }
+        let cheapest = listings
+            .iter()
+            .map(|l| l.price_per_unit)
+            .min_by(|a, b| a.total_cmp(b));
+
+        let estimate = SalePriceEstimate::new(None, None, None);
+        let options = PlanOptions {
+            hq: HqMode::Any,
+            leftovers: None,
+            travel: TravelOptions::default(),
+            vendor_price: None,
+        };
+        let mut related: Vec<_> = self
+            .item_data
+            .recipes
+            .iter()
+            .filter_map(|r| {
+                let used: usize = r
+                    .ingredients
+                    .iter()
+                    .filter(|(id, _)| *id == item_id)
+                    .map(|(_, amount)| amount)
+                    .sum();
+                (r.result_item_id == item_id || used > 0).then_some((
+                    r.result_item_id != item_id,
+                    Reverse(used),
+                    r,
+                ))
+            })
+            .collect();
+        let total_recipes = related.len();
+        related.sort_by_key(|(makes, used, r)| (*makes, *used, r.id));
+        let mut recipes = Vec::new();
+        for (_, _, recipe) in related.into_iter().take(MAX_WATCHED_RECIPES) {
+            let Ok(p) = get_recipe_profit(
+                recipe,
+                &self.item_data,
+                &self.location,
+                1,
+                options.clone(),
+                estimate,
+                TaxModel::new(None),
+                &self.cache,
+                &self.jobs,
+            )
+            .await
+            else {
+                continue;
+            };
+            let previous = self.profits.insert(p.recipe_id, p.margin_per_craft);
+            recipes.push(RecipeChange {
+                recipe_id: p.recipe_id,
+                result_item_id: p.result_item_id,
+                profit: p.margin_per_craft,
+                delta: previous.map(|previous| p.margin_per_craft - previous),
+            });
+        }
+        Some(PriceUpdate {
+            item_id,
+            location: self.location.clone(),
+            cheapest,
+            recipes,
+            total_recipes,
+        })
+    }
+}
+
+//an update for every item right away, then another whenever an item's cached listings change at `location`,
+//be it a refresh or the live feed. the watched listings are asked for every XIVP_CACHE_TIMEOUT to keep them refreshing
+pub(crate) fn watch(
+    item_ids: Vec<usize>,
+    location: String,
+    item_data: Arc<ItemData>,
+    cache: Arc<dyn MarketCache>,
+    jobs: RunningJobs,
+) -> impl Stream<Item = PriceUpdate> {
+    let period = Duration::from_secs(
+        env::var("XIVP_CACHE_TIMEOUT")
+            .unwrap_or(String::from("300"))
+            .parse()
+            .unwrap_or(300)
+            .max(1),
+    );
+    let mut refresh = interval_at(Instant::now() + period, period);
+    refresh.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let watcher = Watcher {
+        refresh,
+        pending: item_ids.iter().copied().collect(),
+        changes: cache.subscribe_listings(),
+        item_ids,
+        location,
+        item_data,
+        cache,
+        jobs,
+        versions: HashMap::new(),
+        profits: HashMap::new(),
+    };
+    stream::unfold(watcher, |mut w| async move {
+        while let Some(item_id) = w.next_change().await {
+            if let Some(update) = w.check(item_id).await {
+                return Some((update, w));
+            }
+        }
+        None
+    })
+}
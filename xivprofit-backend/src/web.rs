@@ -2,25 +2,49 @@ use std::sync::Arc;
 
 use axum::{
     extract::{Query, State},
-    http::StatusCode,
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Json,
 };
-use futures::future::{BoxFuture, Shared};
-use serde::Deserialize;
-use std::collections::HashMap;
+use futures::{future::join_all, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::Infallible, env, time::Duration};
 use tokio::sync::Mutex;
 
 use crate::{
-    cache::InMemoryCache,
-    crafting::{Item, ItemData, Recipe},
-    market::{self, ItemListing},
+    cache::{CacheStats, MarketCache},
+    crafting::{
+        planner::{self, CraftPlan},
+        source::DataSource,
+        Item, ItemData, ItemDataDiff, Job, Recipe, RecipeFilter, SharedItemData,
+    },
+    market::{
+        self,
+        arbitrage::{self, ArbitrageSort, Opportunity},
+        estimator::{EstimatorKind, Quality, SalePriceEstimate},
+        tax::{City, TaxModel},
+        HqMode, ItemListing, MarketError, PlanOptions, PurchasePlan, RunningJobs, SaleHistory,
+        TravelOptions, MAX_AMOUNT,
+    },
+    profit::{
+        self,
+        scan::{self, ProfitSort, ScanEntry, ScanStore},
+        watch, RecipeProfit,
+    },
 };
 
 #[derive(Clone)]
 pub(crate) struct Context {
-    pub(crate) cache: Arc<InMemoryCache>,
-    pub(crate) item_data: Arc<ItemData>,
-    pub(crate) jobs: Arc<Mutex<HashMap<String, Shared<BoxFuture<'static, Vec<ItemListing>>>>>>,
+    pub(crate) cache: Arc<dyn MarketCache>,
+    pub(crate) item_data: Arc<SharedItemData>,
+    //held while a reload is rebuilding ItemData
+    pub(crate) reloading: Arc<Mutex<()>>,
+    pub(crate) jobs: RunningJobs,
+    pub(crate) scans: ScanStore,
 }
 
 #[derive(Deserialize)]
@@ -29,94 +53,592 @@ pub(crate) struct GetItemListingsRequest {
     location: String,
 }
 
+#[derive(Deserialize)]
+pub(crate) struct GetItemsListingsRequest {
+    //comma separated
+    item_ids: String,
+    location: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GetSalePriceRequest {
+    item_id: usize,
+    location: String,
+    estimator: Option<EstimatorKind>,
+    quality: Option<Quality>,
+    n: Option<usize>,
+}
+
 #[derive(Deserialize)]
 pub(crate) struct GetCheapestListingsRequest {
     item_id: usize,
     amount: usize,
     location: String,
     hq: bool,
+    //at least this many HQ units, overrides hq
+    hq_units: Option<usize>,
+    //extra gil to spend on HQ units, overrides hq
+    hq_budget: Option<usize>,
+    //credit units bought past `amount` at their estimated sale price
+    value_leftovers: Option<bool>,
+    estimator: Option<EstimatorKind>,
+    quality: Option<Quality>,
+    n: Option<usize>,
+    //gil a visit to another world is worth avoiding
+    world_penalty: Option<usize>,
+    //shop on at most this many worlds
+    max_worlds: Option<usize>,
+    //world the player starts on, never penalized
+    home_world: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GetProfitRequest {
+    recipe_id: Option<usize>,
+    item_id: Option<usize>,
+    location: String,
+    quantity: usize,
+    hq: bool,
+    value_leftovers: Option<bool>,
+    estimator: Option<EstimatorKind>,
+    quality: Option<Quality>,
+    n: Option<usize>,
+    //gil a visit to another world is worth avoiding
+    world_penalty: Option<usize>,
+    //shop on at most this many worlds
+    max_worlds: Option<usize>,
+    //world the player starts on, never penalized
+    home_world: Option<usize>,
+    //only recipes this job can make, at or below this level
+    job: Option<Job>,
+    max_level: Option<usize>,
+    //where the retainer selling the result is registered, for the tax
+    city: Option<City>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GetRecipesRequest {
+    item_id: Option<usize>,
+    job: Option<Job>,
+    max_level: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GetArbitrageRequest {
+    //a data center, cross world spreads need more than one world
+    location: String,
+    //comma separated, every tradable item sold by a vendor when left out
+    item_ids: Option<String>,
+    #[serde(default)]
+    sort: ArbitrageSort,
+    //how sale prices are judged, the median of recent sales unless set
+    estimator: Option<EstimatorKind>,
+    n: Option<usize>,
+    limit: Option<usize>,
+    //where the retainer selling it is registered, for the tax
+    city: Option<City>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GetCraftPlanRequest {
+    item_id: usize,
+    amount: usize,
+    location: String,
+    hq: bool,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct GetTopProfitsRequest {
+    location: String,
+    #[serde(default)]
+    sort: ProfitSort,
+    #[serde(default)]
+    page: usize,
+    page_size: Option<usize>,
+    job: Option<Job>,
+    max_level: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TopProfitsPage {
+    location: String,
+    in_progress: bool,
+    finished_at: Option<u64>,
+    total: usize,
+    page: usize,
+    page_size: usize,
+    entries: Vec<ScanEntry>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+    //seconds, same as the Retry-After header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+impl IntoResponse for MarketError {
+    fn into_response(self) -> Response {
+        let (status, retry_after) = match self {
+            MarketError::ItemNotFound(_) => (StatusCode::NOT_FOUND, None),
+            MarketError::InvalidLocation(_) | MarketError::InvalidRequest(_) => {
+                (StatusCode::BAD_REQUEST, None)
+            }
+            MarketError::RateLimited { retry_after } => {
+                (StatusCode::SERVICE_UNAVAILABLE, retry_after)
+            }
+            MarketError::Upstream(_) => {
+                warn!("{self}");
+                (StatusCode::BAD_GATEWAY, None)
+            }
+        };
+        let body = Json(ErrorBody {
+            error: self.to_string(),
+            retry_after,
+        });
+        match retry_after {
+            Some(s) => (status, [(header::RETRY_AFTER, s.to_string())], body).into_response(),
+            None => (status, body).into_response(),
+        }
+    }
 }
 
 pub(crate) async fn get_listings(
     State(context): State<Context>,
     r: Query<GetItemListingsRequest>,
-) -> (StatusCode, Json<Vec<ItemListing>>) {
-    let listings = market::get_item_listings(&r.location, r.item_id, &context.cache).await;
-    (StatusCode::OK, Json(listings))
+) -> Result<
+    (
+        StatusCode,
+        [(HeaderName, String); 1],
+        Json<Vec<ItemListing>>,
+    ),
+    MarketError,
+> {
+    let listings = market::get_item_listings(&r.location, r.item_id, &context.cache).await?;
+    Ok((
+        StatusCode::OK,
+        age_header(listings.age),
+        Json(listings.data),
+    ))
+}
+
+//Age in seconds, how long ago the data was fetched from universalis
+fn age_header(age: Duration) -> [(HeaderName, String); 1] {
+    [(header::AGE, age.as_secs().to_string())]
+}
+
+//most item ids one batch request may ask for
+const MAX_BATCH_ITEMS: usize = 500;
+
+pub(crate) async fn get_listings_batch(
+    State(context): State<Context>,
+    r: Query<GetItemsListingsRequest>,
+) -> Result<
+    (
+        StatusCode,
+        [(HeaderName, String); 1],
+        Json<HashMap<usize, Vec<ItemListing>>>,
+    ),
+    MarketError,
+> {
+    let ids = parse_item_ids(&r.item_ids, MAX_BATCH_ITEMS)?;
+    let listings = market::get_items_listings(&r.location, &ids, &context.cache).await?;
+    //the oldest of them
+    let age = listings.values().map(|l| l.age).max().unwrap_or_default();
+    let listings = listings.into_iter().map(|(id, l)| (id, l.data)).collect();
+    Ok((StatusCode::OK, age_header(age), Json(listings)))
+}
+
+//comma separated item ids, at least one and at most `max`
+fn parse_item_ids(item_ids: &str, max: usize) -> Result<Vec<usize>, MarketError> {
+    let ids: Vec<usize> = item_ids
+        .split(',')
+        .map(|id| id.trim().parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| {
+            MarketError::InvalidRequest(format!("item_ids must be numbers: {item_ids}"))
+        })?;
+    if ids.is_empty() || ids.len() > max {
+        return Err(MarketError::InvalidRequest(format!(
+            "between 1 and {max} item_ids are allowed"
+        )));
+    }
+    Ok(ids)
+}
+
+//most item ids one stream may watch
+const MAX_STREAM_ITEMS: usize = 100;
+
+//server-sent `price` events, see profit::watch
+pub(crate) async fn get_stream(
+    State(context): State<Context>,
+    r: Query<GetItemsListingsRequest>,
+) -> Result<Response, MarketError> {
+    let ids = parse_item_ids(&r.item_ids, MAX_STREAM_ITEMS)?;
+    //fails here rather than in the stream on a location universalis doesn't know
+    market::get_items_listings(&r.location, &ids, &context.cache).await?;
+    let updates = watch::watch(
+        ids,
+        r.location.clone(),
+        context.item_data.load(),
+        context.cache.clone(),
+        context.jobs.clone(),
+    )
+    .map(|update| {
+        Ok::<_, Infallible>(
+            Event::default()
+                .event("price")
+                .json_data(update)
+                .unwrap_or_default(),
+        )
+    });
+    Ok(Sse::new(updates)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+pub(crate) async fn get_history(
+    State(context): State<Context>,
+    r: Query<GetItemListingsRequest>,
+) -> Result<(StatusCode, Json<SaleHistory>), MarketError> {
+    let history = market::get_sale_history(&r.location, r.item_id, &context.cache).await?;
+    Ok((StatusCode::OK, Json(history)))
 }
 
 #[axum::debug_handler]
 pub(crate) async fn get_saleprice(
     State(context): State<Context>,
-    r: Query<GetItemListingsRequest>,
-) -> (StatusCode, String) {
-    let mut listings = market::get_item_listings(&r.location, r.item_id, &context.cache).await;
-    listings.sort_by(|a, b| a.price_per_unit.total_cmp(&b.price_per_unit));
-    let saleprice = match listings.first() {
-        Some(l) => l.price_per_unit,
-        None => 0.0,
-    };
-    (StatusCode::OK, saleprice.to_string())
+    r: Query<GetSalePriceRequest>,
+) -> Result<(StatusCode, String), MarketError> {
+    let estimate = SalePriceEstimate::new(r.estimator, r.quality, r.n);
+    let saleprice =
+        market::get_sale_price(&r.location, r.item_id, estimate, &context.cache).await?;
+    Ok((StatusCode::OK, saleprice.to_string()))
 }
 
 pub(crate) async fn get_cheapest_listings(
     State(context): State<Context>,
     r: Query<GetCheapestListingsRequest>,
-) -> (StatusCode, Json<Vec<ItemListing>>) {
-    if r.amount < 1 || r.amount > 1000 {
-        return (StatusCode::BAD_REQUEST, Json(Vec::new()));
+) -> Result<(StatusCode, Json<PurchasePlan>), MarketError> {
+    check_amount(r.amount)?;
+    let item_data = context.item_data.load();
+    let item = item_data
+        .get_item(r.item_id)
+        .ok_or(MarketError::ItemNotFound(r.item_id))?;
+    let estimate = SalePriceEstimate::new(r.estimator, r.quality, r.n);
+    let hq = match (r.hq_units, r.hq_budget) {
+        (Some(k), _) => HqMode::AtLeast(k.min(r.amount)),
+        (None, Some(b)) => HqMode::Budget(b),
+        (None, None) => r.hq.into(),
+    };
+    let options = PlanOptions {
+        hq,
+        leftovers: r.value_leftovers.unwrap_or(false).then_some(estimate),
+        travel: TravelOptions {
+            world_penalty: r.world_penalty.unwrap_or(0),
+            max_worlds: r.max_worlds,
+            home_world: r.home_world,
+            worlds: None,
+        },
+        vendor_price: None,
     }
-    if context
-        .item_data
-        .items
-        .clone()
+    .for_item(Some(item));
+    let plan = market::get_cheapest_combination(
+        r.item_id,
+        r.location.clone(),
+        &context.cache,
+        r.amount,
+        options,
+        &context.jobs,
+    )
+    .await?;
+    Ok((StatusCode::OK, Json(plan)))
+}
+
+//same limit everywhere a plan is bought for
+fn check_amount(amount: usize) -> Result<(), MarketError> {
+    match (1..=MAX_AMOUNT).contains(&amount) {
+        true => Ok(()),
+        false => Err(MarketError::InvalidRequest(format!(
+            "amount must be between 1 and {MAX_AMOUNT}"
+        ))),
+    }
+}
+
+pub(crate) async fn get_profit(
+    State(context): State<Context>,
+    r: Query<GetProfitRequest>,
+) -> Result<(StatusCode, Json<Vec<RecipeProfit>>), MarketError> {
+    let item_data = context.item_data.load();
+    let filter = RecipeFilter {
+        job: r.job,
+        max_level: r.max_level,
+    };
+    let recipes: Vec<&Recipe> = match (r.recipe_id, r.item_id) {
+        (Some(id), _) => item_data.recipes.iter().filter(|x| x.id == id).collect(),
+        (None, Some(id)) => item_data
+            .recipes
+            .iter()
+            .filter(|x| x.result_item_id == id)
+            .collect(),
+        (None, None) => {
+            return Err(MarketError::InvalidRequest(String::from(
+                "recipe_id or item_id is required",
+            )))
+        }
+    };
+    let recipes: Vec<&Recipe> = recipes.into_iter().filter(|x| filter.matches(x)).collect();
+    if recipes.is_empty() {
+        return Err(MarketError::InvalidRequest(String::from(
+            "no recipes match the request",
+        )));
+    }
+    if r.quantity < 1 {
+        return Err(MarketError::InvalidRequest(String::from(
+            "quantity must be at least 1",
+        )));
+    }
+    //same limit as /api/cheapestlistings, per ingredient
+    if recipes
         .iter()
-        .any(|i| i.id == r.item_id)
+        .flat_map(|x| x.ingredients.iter())
+        .any(|(_, amount)| {
+            amount
+                .checked_mul(r.quantity)
+                .is_none_or(|a| a > MAX_AMOUNT)
+        })
     {
-        let listings = market::get_cheapest_combination(
-            r.item_id,
-            r.location.clone(),
+        return Err(MarketError::InvalidRequest(format!(
+            "more than {MAX_AMOUNT} of an ingredient needed"
+        )));
+    }
+
+    let estimate = SalePriceEstimate::new(r.estimator, r.quality, r.n);
+    let options = PlanOptions {
+        hq: r.hq.into(),
+        leftovers: r.value_leftovers.unwrap_or(false).then_some(estimate),
+        travel: TravelOptions {
+            world_penalty: r.world_penalty.unwrap_or(0),
+            max_worlds: r.max_worlds,
+            home_world: r.home_world,
+            worlds: None,
+        },
+        vendor_price: None,
+    };
+    let profits = join_all(recipes.into_iter().map(|x| {
+        profit::get_recipe_profit(
+            x,
+            &item_data,
+            &r.location,
+            r.quantity,
+            options.clone(),
+            estimate,
+            TaxModel::new(r.city),
             &context.cache,
-            r.amount,
-            r.hq,
             &context.jobs,
         )
-        .await;
-        (StatusCode::OK, Json(listings))
-    } else {
-        (StatusCode::BAD_REQUEST, Json(Vec::new()))
-    }
+    }))
+    .await
+    .into_iter()
+    .collect::<Result<Vec<_>, _>>()?;
+    Ok((StatusCode::OK, Json(profits)))
 }
 
-pub(crate) async fn get_items(State(context): State<Context>) -> (StatusCode, Json<Vec<Item>>) {
-    (StatusCode::OK, Json(context.item_data.items.clone()))
+pub(crate) async fn get_arbitrage(
+    State(context): State<Context>,
+    r: Query<GetArbitrageRequest>,
+) -> Result<(StatusCode, Json<Vec<Opportunity>>), MarketError> {
+    let item_data = context.item_data.load();
+    let items: Vec<&Item> = match &r.item_ids {
+        Some(ids) => parse_item_ids(ids, MAX_BATCH_ITEMS)?
+            .iter()
+            .filter_map(|id| item_data.get_item(*id))
+            .filter(|i| !i.untradable)
+            .collect(),
+        None => item_data
+            .items
+            .iter()
+            .filter(|i| !i.untradable && i.vendor_offer().is_some())
+            .collect(),
+    };
+    let estimate = SalePriceEstimate::new(
+        Some(r.estimator.unwrap_or(EstimatorKind::History)),
+        None,
+        r.n,
+    );
+    let mut found = arbitrage::find_opportunities(
+        &r.location,
+        &items,
+        estimate,
+        TaxModel::new(r.city),
+        r.sort,
+        &context.cache,
+    )
+    .await?;
+    found.truncate(r.limit.unwrap_or(100).clamp(1, 500));
+    Ok((StatusCode::OK, Json(found)))
 }
 
-async fn get_all_recipes(context: Context) -> (StatusCode, Json<Vec<Recipe>>) {
-    (StatusCode::OK, Json(context.item_data.recipes.clone()))
+pub(crate) async fn get_craft_plan(
+    State(context): State<Context>,
+    r: Query<GetCraftPlanRequest>,
+) -> Result<(StatusCode, Json<CraftPlan>), MarketError> {
+    check_amount(r.amount)?;
+    let item_data = context.item_data.load();
+    if item_data.get_item(r.item_id).is_none() {
+        return Err(MarketError::ItemNotFound(r.item_id));
+    }
+    let plan = planner::get_cheapest_plan(
+        r.item_id,
+        r.amount,
+        &r.location,
+        r.hq,
+        &item_data,
+        &context.cache,
+        &context.jobs,
+    )
+    .await?;
+    Ok((StatusCode::OK, Json(plan)))
 }
 
-async fn get_recipes_for_item(context: Context, item_id: usize) -> (StatusCode, Json<Vec<Recipe>>) {
-    let mut recipes = context.item_data.recipes.clone();
-    recipes.retain(|r| r.result_item_id == item_id);
-    (StatusCode::OK, Json(recipes))
+pub(crate) async fn get_top_profits(
+    State(context): State<Context>,
+    r: Query<GetTopProfitsRequest>,
+) -> Result<(StatusCode, Json<TopProfitsPage>), MarketError> {
+    market::check_location(&r.location)?;
+    let page_size = r.page_size.unwrap_or(50).clamp(1, 500);
+    let state = context
+        .scans
+        .read()
+        .unwrap()
+        .get(&r.location)
+        .cloned()
+        .unwrap_or_default();
+    if state.needs_scan() {
+        //first request for this location or its results are out of date, scan it in the background.
+        //anything already scanned keeps being served meanwhile
+        tokio::spawn(scan::run_scan(
+            r.location.clone(),
+            context.item_data.load(),
+            context.cache.clone(),
+            context.jobs.clone(),
+            context.scans.clone(),
+        ));
+    }
+    let filter = RecipeFilter {
+        job: r.job,
+        max_level: r.max_level,
+    };
+    let mut ranked = state.ranked(r.sort);
+    ranked.retain(|e| filter.allows(e.job, e.level));
+    let page = TopProfitsPage {
+        location: r.location.clone(),
+        in_progress: state.in_progress || state.finished_at.is_none(),
+        finished_at: state.finished_at,
+        total: ranked.len(),
+        page: r.page,
+        page_size,
+        entries: ranked
+            .into_iter()
+            .skip(r.page.saturating_mul(page_size))
+            .take(page_size)
+            .collect(),
+    };
+    let status = match state.finished_at {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::ACCEPTED,
+    };
+    Ok((status, Json(page)))
+}
+
+pub(crate) async fn get_items(State(context): State<Context>) -> (StatusCode, Json<Vec<Item>>) {
+    (StatusCode::OK, Json(context.item_data.load().items.clone()))
 }
 
 pub(crate) async fn get_recipes(
     State(context): State<Context>,
-    item_id: Option<Query<usize>>,
+    r: Query<GetRecipesRequest>,
 ) -> (StatusCode, Json<Vec<Recipe>>) {
-    match item_id {
-        None => get_all_recipes(context).await,
-        Some(id) => get_recipes_for_item(context, *id).await,
-    }
+    let filter = RecipeFilter {
+        job: r.job,
+        max_level: r.max_level,
+    };
+    let recipes = context
+        .item_data
+        .load()
+        .recipes
+        .iter()
+        .filter(|x| r.item_id.is_none_or(|id| x.result_item_id == id) && filter.matches(x))
+        .cloned()
+        .collect();
+    (StatusCode::OK, Json(recipes))
 }
 
+//only what can be sold, there's no profit in the rest
 pub(crate) async fn get_craftable_items(
     State(context): State<Context>,
 ) -> (StatusCode, Json<Vec<Item>>) {
-    (
-        StatusCode::OK,
-        Json(context.item_data.craftable_items.clone()),
-    )
+    let mut items = context.item_data.load().craftable_items.clone();
+    items.retain(|i| !i.untradable);
+    (StatusCode::OK, Json(items))
+}
+
+//admin endpoints are disabled unless XIVP_ADMIN_TOKEN is set
+fn authorize_admin(headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    let Ok(token) = env::var("XIVP_ADMIN_TOKEN") else {
+        return Err((
+            StatusCode::FORBIDDEN,
+            String::from("admin endpoints are disabled"),
+        ));
+    };
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    match bearer {
+        Some(t) if !token.is_empty() && t == token => Ok(()),
+        _ => Err((
+            StatusCode::UNAUTHORIZED,
+            String::from("invalid admin token"),
+        )),
+    }
+}
+
+pub(crate) async fn get_cache_stats(
+    State(context): State<Context>,
+) -> (StatusCode, Json<CacheStats>) {
+    (StatusCode::OK, Json(context.cache.stats()))
+}
+
+pub(crate) async fn reload_item_data(
+    State(context): State<Context>,
+    headers: HeaderMap,
+) -> Result<Json<ItemDataDiff>, (StatusCode, String)> {
+    authorize_admin(&headers)?;
+    let Ok(_guard) = context.reloading.try_lock() else {
+        return Err((
+            StatusCode::CONFLICT,
+            String::from("reload already in progress"),
+        ));
+    };
+    //build on another task so a bad sheet can't take the handler down with it
+    let data = match tokio::spawn(async { ItemData::new(&DataSource::from_env()).await }).await {
+        Ok(Ok(d)) => d,
+        Ok(Err(e)) => return Err((StatusCode::BAD_GATEWAY, e.to_string())),
+        Err(e) => return Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    };
+    let previous = context.item_data.load();
+    data.validate(&previous)
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    let diff = data.diff(&previous);
+    context.item_data.store(Arc::new(data));
+    info!(
+        "reloaded ItemData: +{}/-{} items, +{}/-{} recipes",
+        diff.added_items.len(),
+        diff.removed_items.len(),
+        diff.added_recipes.len(),
+        diff.removed_recipes.len()
+    );
+    Ok(Json(diff))
 }
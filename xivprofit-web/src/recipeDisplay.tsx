@@ -1,5 +1,5 @@
 import { useState, useEffect } from "react";
-import type { Item, Recipe, ItemListing } from "./types";
+import type { Item, Recipe, ItemListing, PurchasePlan } from "./types";
 import { worlds } from "./data";
 import { backendUrl } from "./config";
 import axios from 'axios';
@@ -24,16 +24,20 @@ export const RecipeDisplay = ({ recipe: r, searchCriteria, items, productSalePri
     const getCheapListings = (itemId: number, location: string, amount: number, hq: boolean) => {
         if (listings.get(listingKey(itemId, amount)) == undefined) {
             const path = 'cheapestlistings?item_id=' + itemId + '&location=' + location + '&amount=' + amount + '&hq=' + hq
-            axios.get<ItemListing[]>(backendUrl + path).then(resp => {
-                listings.set(listingKey(itemId, amount), resp.data)
+            axios.get<PurchasePlan>(backendUrl + path).then(resp => {
+                listings.set(listingKey(itemId, amount), resp.data.listings)
                 setListings(new Map(listings))
             })
         }
     }
 
     useEffect(() => {
-        r.ingredients.map(([id, amount]: [number, number]) =>
-            getCheapListings(id, searchCriteria.location, amount * searchCriteria.quantity, searchCriteria.hq)
+        // one request warms the backend cache for every ingredient, the per ingredient plans are then served from it
+        const ids = r.ingredients.map(([id, _]: [number, number]) => id).join(',')
+        axios.get(backendUrl + 'listings/batch?location=' + searchCriteria.location + '&item_ids=' + ids).finally(() =>
+            r.ingredients.map(([id, amount]: [number, number]) =>
+                getCheapListings(id, searchCriteria.location, amount * searchCriteria.quantity, searchCriteria.hq && (items.find(i => i.id == id)?.can_be_hq ?? true))
+            )
         )
     }, [searchCriteria])
 
@@ -54,6 +58,11 @@ export const RecipeDisplay = ({ recipe: r, searchCriteria, items, productSalePri
     }, [listings])
 
     const ListingDisplay = (listing: ItemListing) => {
+        if (listing.vendor) {
+            return <>
+                Buy {listing.quantity} from vendor for {listing.total_price}g [{listing.price_per_unit}g each]
+            </>
+        }
         return <>
             Buy {listing.quantity} from {listing.retainer_name} on {worlds.find(w => w.id == listing.world_id)?.name} for {listing.total_price}g {listing.hq ? "HQ" : "NQ"} [{listing.price_per_unit}g each]
         </>
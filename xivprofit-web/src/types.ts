@@ -1,6 +1,13 @@
 export interface Item {
   id: number;
   name: string;
+  can_be_hq: boolean;
+  stack_size: number;
+  category: number;
+  untradable: boolean;
+  vendor_price: number;
+  vendor_sell_price: number;
+  item_level: number;
 }
 
 export interface Recipe {
@@ -9,8 +16,19 @@ export interface Recipe {
   result_item_quantity: number,
   //itemid, quantity
   ingredients: Array<[number, number]>,
+  job: Job | null,
+  recipe_level: number,
+  level: number,
+  stars: number,
+  difficulty: number,
+  quality: number,
+  durability: number,
+  required_craftsmanship: number,
+  required_control: number,
 }
 
+export type Job = 'carpenter' | 'blacksmith' | 'armorer' | 'goldsmith' | 'leatherworker' | 'weaver' | 'alchemist' | 'culinarian';
+
 
 export interface ItemListing {
   item_id: number,
@@ -20,6 +38,54 @@ export interface ItemListing {
   total_price: number,
   hq: boolean,
   retainer_name: String,
+  //sold by an NPC, not tied to a world
+  vendor: boolean,
+}
+
+export interface WorldStop {
+  world_id: number,
+  quantity: number,
+  cost: number,
+  listings: ItemListing[],
+}
+
+export interface PurchasePlan {
+  listings: ItemListing[],
+  quantity: number,
+  hq_quantity: number,
+  total_cost: number,
+  surplus: number,
+  surplus_value: number,
+  travel_cost: number,
+  net_cost: number,
+  fulfilled: boolean,
+  route: WorldStop[],
+  listings_version: number,
+  //unix seconds the listings were fetched
+  fetched_at: number | null,
+}
+
+export interface Revenue {
+  gross: number,
+  tax: number,
+  net: number,
+}
+
+export type ArbitrageSource =
+  | { from: 'vendor' }
+  | { from: 'world', world_id: number, quantity: number };
+
+export interface Opportunity {
+  item_id: number,
+  name: string,
+  hq: boolean,
+  buy: ArbitrageSource,
+  buy_price: number,
+  purchase_tax: number,
+  sell_world: number | null,
+  revenue: Revenue,
+  profit: number,
+  sale_velocity: number,
 }
 
 export interface datacenter {